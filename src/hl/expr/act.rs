@@ -0,0 +1,190 @@
+use crate::hl::expr::constant::full;
+use crate::hl::expr::custom::custom_op_with_jvp;
+use crate::hl::expr::{Eval, Expr, Value};
+use std::f64::consts::{FRAC_2_SQRT_PI, SQRT_2};
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Constant with the shape of `self`
+    fn splat(&self, v: f64) -> Expr<T, E> {
+        full(self.shape().clone(), v)
+    }
+
+    /// y = max(x, 0)
+    pub fn relu(self) -> Expr<T, E> {
+        self.clone().gtz() * self
+    }
+
+    /// y = x for positive x, `slope * x` otherwise
+    pub fn leaky_relu(self, slope: f64) -> Expr<T, E> {
+        let k = self.clone().gtz() * self.splat(1.0 - slope) + self.splat(slope);
+        k * self
+    }
+
+    /// y = x for positive x, `alpha * (e^x - 1)` otherwise
+    pub fn elu(self, alpha: f64) -> Expr<T, E> {
+        let pos = self.clone().gtz();
+        // Positive inputs reach `expm1` as zeros, so they can't overflow
+        let neg = (self.splat(1.0) - &pos) * &self;
+        pos * &self + self.splat(alpha) * neg.expm1()
+    }
+
+    /// y = 1 / (1 + e^-x), computed as `(1 + tanh(x / 2)) / 2` which doesn't overflow
+    pub fn sigmoid(self) -> Expr<T, E> {
+        let half = self.splat(0.5);
+        half.clone() + half.clone() * (half * self).tanh()
+    }
+
+    /// y = x * sigmoid(x)
+    pub fn silu(self) -> Expr<T, E> {
+        self.clone().sigmoid() * self
+    }
+
+    /// y = ln(1 + e^x), computed as `relu(x) + ln(1 + e^-|x|)`. The derivative is `sigmoid(x)` in
+    /// both modes, also at 0, where both parts have a kink.
+    pub fn softplus(self) -> Expr<T, E> {
+        custom_op_with_jvp(
+            self,
+            |x| {
+                let tail = (-x.clone().abs().astype::<T>()).exp().log1p();
+                x.relu() + tail
+            },
+            |x, g| g * x.astype::<E::Grad>().sigmoid(),
+            |x, t| t * x.astype::<E::Grad>().sigmoid(),
+        )
+    }
+
+    /// y = x * tanh(softplus(x))
+    pub fn mish(self) -> Expr<T, E> {
+        self.clone().softplus().tanh() * self
+    }
+
+    /// Exact GELU, y = x * (1 + erf(x / sqrt(2))) / 2
+    pub fn gelu(self) -> Expr<T, E> {
+        let cdf = self.splat(1.0) + (self.clone() * self.splat(1.0 / SQRT_2)).erf();
+        self.splat(0.5) * &self * cdf
+    }
+
+    /// GELU approximated with tanh, y = x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 x^3))) / 2
+    pub fn gelu_tanh(self) -> Expr<T, E> {
+        let cube = self.clone() * &self * &self;
+        let inner =
+            self.splat(FRAC_2_SQRT_PI / SQRT_2) * (self.clone() + self.splat(0.044715) * cube);
+        self.splat(0.5) * &self * (self.splat(1.0) + inner.tanh())
+    }
+
+    /// Clamps `x` to `[min, max]`
+    pub fn hardtanh(self, min: f64, max: f64) -> Expr<T, E> {
+        self.clamp(min, max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::constant::ones;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::test::{run, TestEv};
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::arr1;
+    use std::f64::consts::LN_2;
+
+    #[test]
+    fn test_stable() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![4]);
+        let outs = [
+            x.clone().sigmoid(),
+            x.clone().softplus(),
+            x.clone().elu(1.0),
+            x.clone().mish(),
+            x.clone().silu(),
+        ];
+        // Backward rules have to stay finite at the same inputs
+        let grads: Vec<_> = outs
+            .iter()
+            .map(|y| y.vjp(&mut e, ones(shape![4]), &x))
+            .collect();
+        let xb = x.eval(&mut e);
+        let ys: Vec<_> = outs.iter().chain(&grads).map(|y| y.eval(&mut e)).collect();
+
+        let mut cpu = Cpu::new();
+        cpu.set(xb, arr1(&[-1000.0, -50.0, 50.0, 1000.0]).into_dyn());
+        for y in ys {
+            let v = cpu.get(e.emitter(), y);
+            assert!(v.iter().all(|v| v.is_finite()), "{v}");
+        }
+
+        let hard = x.hardtanh(-1.0, 1.0).eval(&mut e);
+        assert_eq!(
+            cpu.get(e.emitter(), hard),
+            arr1(&[-1.0, -1.0, 1.0, 1.0]).into_dyn()
+        );
+    }
+
+    /// Every activation, in the order of [`REF`]
+    fn all(x: &Expr<f32, TestEv>) -> Vec<Expr<f32, TestEv>> {
+        vec![
+            x.clone().relu(),
+            x.clone().leaky_relu(0.1),
+            x.clone().elu(1.0),
+            x.clone().sigmoid(),
+            x.clone().silu(),
+            x.clone().softplus(),
+            x.clone().mish(),
+            x.clone().gelu(),
+            x.clone().gelu_tanh(),
+            x.clone().hardtanh(-1.0, 1.0),
+        ]
+    }
+
+    const X: [f64; 4] = [-2.0, -0.5, 0.0, 1.5];
+
+    /// Reference values at [`X`]
+    const REF: [[f64; 4]; 10] = [
+        [0.0, 0.0, 0.0, 1.5],
+        [-0.2, -0.05, 0.0, 1.5],
+        [-0.86466472, -0.39346934, 0.0, 1.5],
+        [0.11920292, 0.37754067, 0.5, 0.81757448],
+        [-0.23840584, -0.18877033, 0.0, 1.22636171],
+        [0.12692801, 0.47407698, LN_2, 1.70141328],
+        [-0.25250148, -0.22074377, 0.0, 1.40337827],
+        [-0.04550026, -0.15426877, 0.0, 1.3997892],
+        [-0.04540231, -0.15428599, 0.0, 1.39957158],
+        [-1.0, -0.5, 0.0, 1.0],
+    ];
+
+    #[test]
+    fn test_values() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![4]);
+        let ys = run(&mut e, &[(&x, &X)], &all(&x));
+        for (y, r) in ys.iter().zip(REF) {
+            for (y, r) in y.iter().zip(r) {
+                assert!((y - r).abs() < 1e-6, "{y} != {r}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_jvp_matches_vjp() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![4]);
+        let outs = all(&x);
+        // Elementwise, so the Jacobian is diagonal and both modes give its diagonal
+        let grads: Vec<_> = outs
+            .iter()
+            .map(|y| y.vjp(&mut e, ones(shape![4]), &x))
+            .collect();
+        x.set_tangent(ones(shape![4]));
+        let tangents: Vec<_> = outs.iter().map(|y| y.jvp(&mut e)).collect();
+
+        let g = run(&mut e, &[(&x, &X)], &grads);
+        let t = run(&mut e, &[(&x, &X)], &tangents);
+        for (g, t) in g.iter().flatten().zip(t.iter().flatten()) {
+            assert!((g - t).abs() < 1e-6, "{g} != {t}");
+        }
+        // softplus' = sigmoid, also at the kink
+        assert_eq!(t[5][2], 0.5);
+    }
+}
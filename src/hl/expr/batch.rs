@@ -0,0 +1,176 @@
+use crate::hl::expr::param::param;
+use crate::hl::expr::{Eval, Expr, Node, Value};
+use crate::hl::module::Module;
+use std::any::Any;
+use std::collections::HashMap;
+
+/// Rewrites a graph built for a single example into one working on a whole batch, which is added as
+/// a new leading axis.
+///
+/// Nodes are rewritten at most once, expressions that don't depend on the batched input are kept as
+/// they are and only broadcast where they meet batched ones.
+pub struct Batch {
+    size: usize,
+    // Rewritten nodes by key of the original, `None` if the node is not batched
+    done: HashMap<usize, Option<Box<dyn Any>>>,
+}
+
+impl Batch {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            done: HashMap::new(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Uses `batched` in place of `x` in the rewritten graph
+    pub fn insert<T: Value, E: Eval>(&mut self, x: &Expr<T, E>, batched: Expr<T, E>) {
+        self.done.insert(x.key(), Some(Box::new(batched)));
+    }
+
+    /// Batched version of `x`, or `None` if it does not depend on the batched input
+    pub fn get<T: Value, E: Eval>(&mut self, x: &Expr<T, E>) -> Option<Expr<T, E>> {
+        if let Some(done) = self.done.get(&x.key()) {
+            return done
+                .as_ref()
+                .map(|b| b.downcast_ref::<Expr<T, E>>().unwrap().clone());
+        }
+
+        let out = x.0._impl.vmap(self);
+        self.done
+            .insert(x.key(), out.clone().map(|o| Box::new(o) as Box<dyn Any>));
+        out
+    }
+
+    /// Batched version of `x`, repeating it over the batch if it doesn't depend on the batched input
+    pub fn get_or_broadcast<T: Value, E: Eval>(&mut self, x: &Expr<T, E>) -> Expr<T, E> {
+        self.get(x)
+            .unwrap_or_else(|| x.clone().broadcast(0, self.size))
+    }
+}
+
+/// Lifts `f`, written for a single example, to work on a batch of them stacked along the first axis
+pub fn vmap<T, E, M>(f: M) -> impl Fn(Expr<T, E>) -> Expr<T, E>
+where
+    T: Value,
+    E: Eval,
+    M: Module<Expr<T, E>, Output = Expr<T, E>>,
+{
+    move |xs: Expr<T, E>| {
+        let x = param(xs.shape().remove(0));
+        let y = f.forward(x.clone());
+
+        let mut b = Batch::new(xs.shape()[0]);
+        b.insert(&x, xs);
+        b.get_or_broadcast(&y)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::batch::vmap;
+    use crate::hl::expr::constant::zeros;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::{arr2, arr3};
+
+    #[test]
+    fn test_vmap() {
+        let mut e = TestEv::new();
+        let w: Expr<f32, TestEv> = param(shape![3]);
+        let b: Expr<f32, TestEv> = param(shape![3]);
+        let f = move |x: Expr<f32, TestEv>| x.clone() * &w + &b;
+
+        let xs: Expr<f32, TestEv> = param(shape![4, 3]);
+        let ys = vmap(f.clone())(xs.clone());
+        assert_eq!(ys.shape(), &shape![4, 3]);
+        ys.eval(&mut e);
+
+        ys.backward(&mut e);
+        assert_eq!(xs.grad().unwrap().shape(), &shape![4, 3]);
+
+        // Outputs independent of the input are repeated over the batch
+        let c: Expr<f32, TestEv> = param(shape![2]);
+        let ys = vmap(move |_: Expr<f32, TestEv>| c.clone())(xs);
+        assert_eq!(ys.shape(), &shape![4, 2]);
+    }
+
+    #[test]
+    fn test_vmap_reduce() {
+        let w: Expr<f32, TestEv> = param(shape![3]);
+        let f = {
+            let w = w.clone();
+            move |x: Expr<f32, TestEv>| (x * &w).sum_axis(0)
+        };
+
+        let xs: Expr<f32, TestEv> = param(shape![5, 3]);
+        let ys = vmap(f)(xs.clone());
+        assert_eq!(ys.shape(), &shape![5]);
+
+        let mut e = TestEv::new();
+        ys.backward(&mut e);
+        assert_eq!(w.grad().unwrap().shape(), &shape![3]);
+    }
+
+    #[test]
+    fn test_vmap_softmax() {
+        let w: Expr<f32, TestEv> = param(shape![3]);
+        let f = {
+            let w = w.clone();
+            move |x: Expr<f32, TestEv>| (x * &w).softmax(0)
+        };
+
+        let xs: Expr<f32, TestEv> = param(shape![5, 3]);
+        let ys = vmap(f)(xs.clone());
+        assert_eq!(ys.shape(), &shape![5, 3]);
+
+        let mut e = TestEv::new();
+        ys.backward(&mut e);
+        assert_eq!(w.grad().unwrap().shape(), &shape![3]);
+        assert_eq!(xs.grad().unwrap().shape(), &shape![5, 3]);
+    }
+
+    #[test]
+    fn test_vmap_gather() {
+        // Picks the row with the largest sum and adds it to the first row of zeros
+        let pick = |x: Expr<f32, TestEv>| {
+            let i = x.clone().sum(&[1], false).argmax(0, false);
+            x.gather(0, i)
+        };
+        let put = |x: Expr<f32, TestEv>| {
+            let i = x.clone().sum(&[1], false).argmax(0, false);
+            zeros(shape![3, 2]).index_add(0, i, x.index(0, 0))
+        };
+
+        let mut e = TestEv::new();
+        let xs: Expr<f32, TestEv> = param(shape![2, 3, 2]);
+        let picked = vmap(pick)(xs.clone());
+        let put = vmap(put)(xs.clone());
+        assert_eq!(picked.shape(), &shape![2, 2]);
+        assert_eq!(put.shape(), &shape![2, 3, 2]);
+
+        let xb = xs.eval(&mut e);
+        let (pb, ub) = (picked.eval(&mut e), put.eval(&mut e));
+        let mut cpu = Cpu::new();
+        let x = arr3(&[
+            [[1.0, 2.0], [5.0, 6.0], [3.0, 4.0]],
+            [[9.0, 9.0], [1.0, 0.0], [2.0, 3.0]],
+        ]);
+        cpu.set(xb, x.into_dyn());
+        let bld = e.emitter();
+        let rows = arr2(&[[5.0, 6.0], [9.0, 9.0]]);
+        assert_eq!(cpu.get(bld, pb), rows.into_dyn());
+        let put = arr3(&[
+            [[0.0, 0.0], [1.0, 2.0], [0.0, 0.0]],
+            [[9.0, 9.0], [0.0, 0.0], [0.0, 0.0]],
+        ]);
+        assert_eq!(cpu.get(bld, ub), put.into_dyn());
+    }
+}
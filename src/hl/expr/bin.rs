@@ -1,13 +1,25 @@
-use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Ten, Value, Visitor};
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::broadcast::broadcast_pair;
+use crate::hl::expr::constant::full;
+use crate::hl::expr::{
+    sum_opt, Eval, Expr, ExprData, ExprImpl, Node, Promote, Ten, Value, Visitor, C128, C64,
+};
 use crate::hl::shape::Shape;
 use crate::ml::{BufId, OpType};
 use std::ops::{Add, Deref, Div, Mul, Sub};
+use half::{bf16, f16};
 use num::traits::Inv;
 
 #[derive(Debug)]
 pub(crate) enum BinOp {
     Add,
     Mul,
+    /// Integer division, rounding towards zero. Floats multiply by the reciprocal instead.
+    Div,
+    /// y = l^r
+    Pow,
+    Min,
+    Max,
 }
 
 #[derive(Debug)]
@@ -28,30 +40,142 @@ impl<T: Value, E: Eval> ExprImpl<T, E> for Bin<T, E> {
         self.r.accept(v);
     }
 
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.l);
+        f(&self.r);
+    }
+
     fn eval(&self, id: u64, e: &mut E) -> BufId {
         let l = self.l.eval(e);
         let r = self.r.eval(e);
 
+        let op = match self.op {
+            BinOp::Add => OpType::Add,
+            BinOp::Mul => OpType::Mul,
+            BinOp::Div => OpType::Div,
+            BinOp::Pow => OpType::Pow,
+            BinOp::Min => OpType::Minimum,
+            BinOp::Max => OpType::Maximum,
+        };
+        e.emitter().emit(op, &self.shape, l, r)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let (tl, tr) = (self.l.tangent(), self.r.tangent());
         match self.op {
-            BinOp::Add => e.emitter().emit(OpType::Add, &self.shape, l, r),
-            BinOp::Mul => e.emitter().emit(OpType::Mul, &self.shape, l, r),
+            BinOp::Add => sum_opt::<E>(tl, tr),
+            BinOp::Mul => sum_opt::<E>(
+                tl.map(|t| t * self.r.astype()),
+                tr.map(|t| self.l.astype() * t),
+            ),
+            BinOp::Div | BinOp::Pow | BinOp::Min | BinOp::Max => {
+                sum_opt::<E>(tl.map(|t| t * self.dl()), tr.map(|t| t * self.dr()))
+            }
         }
     }
 
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        if b.get(&self.l).is_none() && b.get(&self.r).is_none() {
+            return None;
+        }
+        let (l, r) = (b.get_or_broadcast(&self.l), b.get_or_broadcast(&self.r));
+        Some(match self.op {
+            BinOp::Add => l + r,
+            BinOp::Mul => l * r,
+            BinOp::Div => l / r,
+            BinOp::Pow => l.pow(r),
+            BinOp::Min => l.minimum(r),
+            BinOp::Max => l.maximum(r),
+        })
+    }
+
     fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
         match self.op {
             BinOp::Add => {
-                self.l.backward(e, || grad.clone());
-                self.r.backward(e, || grad);
+                self.l.accumulate(e, || grad.clone());
+                self.r.accumulate(e, || grad);
             }
+            // Conjugated for complex values, see `Un::backward`
             BinOp::Mul => {
-                self.l.backward(e, || self.l.astype() * &grad);
-                self.r.backward(e, || self.r.astype() * &grad);
+                self.l.accumulate(e, || self.r.astype().conj() * &grad);
+                self.r.accumulate(e, || self.l.astype().conj() * &grad);
+            }
+            BinOp::Div | BinOp::Pow | BinOp::Min | BinOp::Max => {
+                self.l.accumulate(e, || self.dl().conj() * &grad);
+                self.r.accumulate(e, || self.dr().conj() * &grad);
             }
         }
     }
 }
 
+impl<T: Value, E: Eval> Bin<T, E> {
+    /// Derivative with respect to `l`
+    fn dl(&self) -> Expr<E::Grad, E> {
+        let (l, r) = (self.l.astype::<E::Grad>(), self.r.astype::<E::Grad>());
+        let one = || full::<E::Grad, E>(self.shape.clone(), 1.0);
+        match self.op {
+            BinOp::Add => one(),
+            BinOp::Mul => r,
+            // Piecewise constant
+            BinOp::Div => full(self.shape.clone(), 0.0),
+            BinOp::Pow => r.clone() * l.pow(r - one()),
+            BinOp::Min => tie_split(self.l.clone().lt(&self.r), self.l.clone().eq(&self.r)),
+            BinOp::Max => tie_split(self.l.clone().gt(&self.r), self.l.clone().eq(&self.r)),
+        }
+    }
+
+    /// Derivative with respect to `r`
+    fn dr(&self) -> Expr<E::Grad, E> {
+        let l = self.l.astype::<E::Grad>();
+        match self.op {
+            BinOp::Add => full(self.shape.clone(), 1.0),
+            BinOp::Mul => l,
+            BinOp::Div => full(self.shape.clone(), 0.0),
+            BinOp::Pow => l.clone().pow(self.r.astype()) * l.log(),
+            BinOp::Min => tie_split(self.r.clone().lt(&self.l), self.r.clone().eq(&self.l)),
+            BinOp::Max => tie_split(self.r.clone().gt(&self.l), self.r.clone().eq(&self.l)),
+        }
+    }
+}
+
+/// Subgradient of min and max, `1` where the side wins and `1/2` on ties
+fn tie_split<E: Eval>(wins: Expr<bool, E>, tie: Expr<bool, E>) -> Expr<E::Grad, E> {
+    let half = full::<E::Grad, E>(tie.shape().clone(), 0.5);
+    wins.astype::<E::Grad>() + tie.astype::<E::Grad>() * half
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Both sides are broadcast to a common shape first, see [`Shape::broadcast_with`]
+    fn bin(self, op: BinOp, rhs: Expr<T, E>) -> Expr<T, E> {
+        let (l, r) = broadcast_pair(self, rhs);
+        Expr(ExprData::new(Bin {
+            op,
+            shape: l.shape().clone(),
+            l,
+            r,
+        }))
+    }
+
+    /// Elementwise power `self^rhs`. Unlike the arithmetic operators this takes no mixed element
+    /// types, convert the exponent with [`Expr::astype`] first.
+    pub fn pow(self, rhs: impl Into<Expr<T, E>>) -> Expr<T, E> {
+        self.bin(BinOp::Pow, rhs.into())
+    }
+    /// Power with a constant exponent
+    pub fn powf(self, p: f64) -> Expr<T, E> {
+        let p = full(self.shape().clone(), p);
+        self.pow(p)
+    }
+    /// Elementwise minimum of operands with the same element type. Ties split the gradient evenly.
+    pub fn minimum(self, rhs: impl Into<Expr<T, E>>) -> Expr<T, E> {
+        self.bin(BinOp::Min, rhs.into())
+    }
+    /// Elementwise maximum of operands with the same element type. Ties split the gradient evenly.
+    pub fn maximum(self, rhs: impl Into<Expr<T, E>>) -> Expr<T, E> {
+        self.bin(BinOp::Max, rhs.into())
+    }
+}
+
 impl<T, E, RHS> Add<RHS> for Expr<T, E>
 where
     T: Value,
@@ -61,12 +185,7 @@ where
     type Output = Expr<T, E>;
 
     fn add(self, rhs: RHS) -> Self::Output {
-        Expr(ExprData::new(Bin {
-            op: BinOp::Add,
-            shape: self.shape().clone(),
-            l: self,
-            r: rhs.into(),
-        }))
+        self.bin(BinOp::Add, rhs.into())
     }
 }
 
@@ -92,12 +211,7 @@ where
     type Output = Expr<T, E>;
 
     fn mul(self, rhs: RHS) -> Self::Output {
-        Expr(ExprData::new(Bin {
-            op: BinOp::Mul,
-            shape: self.shape().clone(),
-            l: self,
-            r: rhs.into(),
-        }))
+        self.bin(BinOp::Mul, rhs.into())
     }
 }
 
@@ -110,6 +224,42 @@ where
     type Output = Expr<T, E>;
 
     fn div(self, rhs: RHS) -> Self::Output {
-        self * rhs.into().inv()
+        if T::DTYPE.is_float() {
+            self * rhs.into().inv()
+        } else {
+            self.bin(BinOp::Div, rhs.into())
+        }
     }
 }
+
+// Ops mixing element types cast both sides to the promoted type first. Only the operator traits
+// can be overloaded this way, methods like `pow`, `maximum` and `lt` need matching types.
+macro_rules! mixed {
+    ($l:ty: $($r:ty),*) => {$(
+        mixed!(@op $l, $r, Add, add);
+        mixed!(@op $l, $r, Sub, sub);
+        mixed!(@op $l, $r, Mul, mul);
+        mixed!(@op $l, $r, Div, div);
+    )*};
+    (@op $l:ty, $r:ty, $tr:ident, $f:ident) => {
+        impl<E: Eval> $tr<Expr<$r, E>> for Expr<$l, E> {
+            type Output = Expr<<$l as Promote<$r>>::Output, E>;
+
+            fn $f(self, rhs: Expr<$r, E>) -> Self::Output {
+                type O = <$l as Promote<$r>>::Output;
+                $tr::$f(self.astype::<O>(), rhs.astype::<O>())
+            }
+        }
+    };
+}
+
+mixed!(bool: u8, i32, i64, f16, bf16, f32, f64, C64, C128);
+mixed!(u8: bool, i32, i64, f16, bf16, f32, f64, C64, C128);
+mixed!(i32: bool, u8, i64, f16, bf16, f32, f64, C64, C128);
+mixed!(i64: bool, u8, i32, f16, bf16, f32, f64, C64, C128);
+mixed!(f16: bool, u8, i32, i64, bf16, f32, f64, C64, C128);
+mixed!(bf16: bool, u8, i32, i64, f16, f32, f64, C64, C128);
+mixed!(f32: bool, u8, i32, i64, f16, bf16, f64, C64, C128);
+mixed!(f64: bool, u8, i32, i64, f16, bf16, f32, C64, C128);
+mixed!(C64: bool, u8, i32, i64, f16, bf16, f32, f64, C128);
+mixed!(C128: bool, u8, i32, i64, f16, bf16, f32, f64, C64);
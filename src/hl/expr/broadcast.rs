@@ -0,0 +1,106 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug)]
+/// Inserts a new axis, repeating the input along it.
+pub struct Broadcast<T: Value, E: Eval> {
+    axis: usize,
+    count: usize,
+    shape: Shape,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Broadcast<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        e.emitter().emit(
+            OpType::Broadcast {
+                axis: self.axis,
+                count: self.count,
+            },
+            &self.shape,
+            x,
+            BufId::default(),
+        )
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        Some(self.x.tangent()?.broadcast(self.axis, self.count))
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        Some(b.get(&self.x)?.broadcast(self.axis + 1, self.count))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        self.x.accumulate(e, || grad.sum_axis(self.axis));
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Inserts a new axis at `axis`, repeating this tensor `count` times along it
+    pub fn broadcast(self, axis: usize, count: usize) -> Expr<T, E> {
+        Expr(ExprData::new(Broadcast {
+            axis,
+            count,
+            shape: self.shape().insert(axis as isize, count),
+            x: self,
+        }))
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Repeats this tensor to `shape` following NumPy rules. Axes are aligned from the end, missing
+    /// leading axes are added and axes of size 1 are repeated.
+    pub fn broadcast_to(self, shape: &Shape) -> Expr<T, E> {
+        if self.shape() == shape {
+            return self;
+        }
+        let (from, to) = (self.shape()[..].to_vec(), &shape[..]);
+        assert!(
+            from.len() <= to.len(),
+            "Can't broadcast {:?} to {shape:?}",
+            self.shape()
+        );
+        let lead = to.len() - from.len();
+
+        let mut y = self;
+        for (i, (f, t)) in from.iter().zip(&to[lead..]).enumerate() {
+            if f != t {
+                assert_eq!(*f, 1, "Can't broadcast {from:?} to {shape:?}");
+                let dropped = y.shape().remove(i as isize);
+                y = y.reshape(dropped).broadcast(i, *t);
+            }
+        }
+        for a in (0..lead).rev() {
+            y = y.broadcast(0, to[a]);
+        }
+        y
+    }
+}
+
+/// Broadcasts `l` and `r` to their common shape, panics if there is none
+pub(crate) fn broadcast_pair<T: Value, E: Eval>(
+    l: Expr<T, E>,
+    r: Expr<T, E>,
+) -> (Expr<T, E>, Expr<T, E>) {
+    let shape = l
+        .shape()
+        .broadcast_with(r.shape())
+        .unwrap_or_else(|| panic!("Can't broadcast {:?} with {:?}", l.shape(), r.shape()));
+    (l.broadcast_to(&shape), r.broadcast_to(&shape))
+}
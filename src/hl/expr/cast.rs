@@ -0,0 +1,176 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+use std::marker::PhantomData;
+
+#[derive(Debug)]
+/// Converts elements of type `F` into `T`.
+pub struct Cast<F: Value, T: Value, E: Eval> {
+    x: Expr<F, E>,
+    _p: PhantomData<T>,
+}
+
+impl<F: Value, T: Value, E: Eval> Cast<F, T, E> {
+    pub(crate) fn new(x: Expr<F, E>) -> Self {
+        Self {
+            x,
+            _p: Default::default(),
+        }
+    }
+}
+
+impl<F: Value, T: Value, E: Eval> ExprImpl<T, E> for Cast<F, T, E> {
+    fn shape(&self) -> &Shape {
+        self.x.shape()
+    }
+
+    // Visitors are typed by the element type, so they can't continue into the input
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {}
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        e.emitter().emit(
+            OpType::Cast { to: T::DTYPE },
+            self.x.shape(),
+            x,
+            BufId::default(),
+        )
+    }
+
+    // Real results only keep the real part of the tangent
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let t = self.x.tangent()?;
+        if F::DTYPE.is_complex() && !T::DTYPE.is_complex() {
+            return Some(t.real().astype());
+        }
+        Some(t)
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        Some(b.get(&self.x)?.astype())
+    }
+
+    // Gradients are always held in `E::Grad`, so they pass through unchanged. Real inputs only
+    // see the real part of gradients of complex results.
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        if T::DTYPE.is_complex() && !F::DTYPE.is_complex() {
+            self.x.accumulate(e, || grad.real().astype());
+        } else {
+            self.x.accumulate(e, || grad);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::cast::Cast;
+    use crate::hl::expr::constant::full;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr, ExprData, Promote, Value, C128, C64};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::ml::DType;
+    use crate::shape;
+    use half::{bf16, f16};
+    use ndarray::arr1;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_cast() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        assert!(Rc::ptr_eq(&x.astype::<f32>().0, &x.0));
+
+        let y: Expr<f32, TestEv> = Expr(ExprData::new(Cast::<f32, f32, _>::new(x.clone())));
+        let out = y.eval(&mut e);
+        assert_eq!(e.emitter().dtype(out), DType::F32);
+        assert_ne!(out, x.eval(&mut e));
+
+        y.backward(&mut e);
+        assert!(Rc::ptr_eq(&x.grad().unwrap().0, &y.grad().unwrap().0));
+    }
+
+    #[test]
+    fn test_mixed() {
+        let mut e = TestEv::new();
+        let a: Expr<f32, TestEv> = param(shape![3]);
+        let b: Expr<f64, TestEv> = param(shape![3]);
+        let m: Expr<bool, TestEv> = param(shape![3]);
+        assert!(!m.requires_grad());
+
+        let c: Expr<f64, TestEv> = a.clone() + b.clone();
+        let out = c.eval(&mut e);
+        assert_eq!(e.emitter().dtype(out), DType::F64);
+
+        let masked: Expr<f32, TestEv> = a.clone() * m;
+        masked.backward(&mut e);
+        assert!(a.grad().is_some());
+
+        let i: Expr<i32, TestEv> = a.astype();
+        assert!(!i.requires_grad());
+        let out = i.eval(&mut e);
+        assert_eq!(e.emitter().dtype(out), DType::I32);
+    }
+
+    #[test]
+    fn test_complex_to_real_jvp() {
+        let mut e: TestEv<C128> = TestEv::with_grad();
+        let z: Expr<C128, TestEv<C128>> = param(shape![2]);
+        let y: Expr<f64, TestEv<C128>> = z.astype();
+        z.set_tangent(full(shape![2], C128::new(1.0, 2.0)));
+        let t = y.jvp(&mut e);
+
+        let (zb, tb) = (z.eval(&mut e), t.eval(&mut e));
+        let mut cpu = Cpu::new();
+        cpu.set(zb, arr1(&[1.0, -3.0]).into_dyn());
+        assert_eq!(
+            cpu.get_complex(e.emitter(), tb),
+            arr1(&[C128::new(1.0, 0.0); 2]).into_dyn()
+        );
+    }
+
+    #[test]
+    fn test_int_div() {
+        let mut e = TestEv::new();
+        let x: Expr<i32, TestEv> = param(shape![4]);
+        let y: Expr<i32, TestEv> = param(shape![4]);
+        let (xb, yb) = (x.eval(&mut e), y.eval(&mut e));
+        let q = (x.clone() / y.clone()).eval(&mut e);
+        // Mixed with a float, both sides become floats first
+        let f = (x / y.astype::<f32>()).eval(&mut e);
+
+        let mut cpu = Cpu::new();
+        cpu.set(xb, arr1(&[7.0, -7.0, 9.0, 1.0]).into_dyn());
+        cpu.set(yb, arr1(&[2.0, 2.0, -4.0, 4.0]).into_dyn());
+        assert_eq!(
+            cpu.get(e.emitter(), q),
+            arr1(&[3.0, -3.0, -2.0, 0.0]).into_dyn()
+        );
+        assert_eq!(
+            cpu.get(e.emitter(), f),
+            arr1(&[3.5, -3.5, -2.25, 0.25]).into_dyn()
+        );
+    }
+
+    #[test]
+    fn test_promote_matches_dtype() {
+        macro_rules! check {
+            ($($l:ty),*) => {$(
+                check!(@row $l: bool, u8, i32, i64, f16, bf16, f32, f64, C64, C128);
+            )*};
+            (@row $l:ty: $($r:ty),*) => {$(
+                assert_eq!(
+                    <<$l as Promote<$r>>::Output as Value>::DTYPE,
+                    <$l>::DTYPE.promote(<$r>::DTYPE),
+                    "{} + {}", stringify!($l), stringify!($r)
+                );
+            )*};
+        }
+        check!(bool, u8, i32, i64, f16, bf16, f32, f64, C64, C128);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::zeros;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug)]
+/// Joins two tensors along an axis.
+struct Cat<T: Value, E: Eval> {
+    axis: usize,
+    shape: Shape,
+    l: Expr<T, E>,
+    r: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Cat<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.l.accept(v);
+        self.r.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.l);
+        f(&self.r);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let l = self.l.eval(e);
+        let r = self.r.eval(e);
+        e.emitter()
+            .emit(OpType::Cat { axis: self.axis }, &self.shape, l, r)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let (tl, tr) = (self.l.tangent(), self.r.tangent());
+        if tl.is_none() && tr.is_none() {
+            return None;
+        }
+        let zero = |x: &Expr<T, E>| zeros(x.shape().clone());
+        let tl = tl.unwrap_or_else(|| zero(&self.l));
+        let tr = tr.unwrap_or_else(|| zero(&self.r));
+        Some(cat(&[tl, tr], self.axis as isize))
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        if b.get(&self.l).is_none() && b.get(&self.r).is_none() {
+            return None;
+        }
+        let (l, r) = (b.get_or_broadcast(&self.l), b.get_or_broadcast(&self.r));
+        Some(cat(&[l, r], self.axis as isize + 1))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        let axis = self.axis as isize;
+        let (nl, nr) = (self.l.shape()[axis], self.r.shape()[axis]);
+        self.l.accumulate(e, || grad.clone().narrow(axis, 0, nl));
+        self.r.accumulate(e, || grad.narrow(axis, nl, nr));
+    }
+}
+
+/// Joins `xs` along `axis`, all other axes have to match
+pub fn cat<T: Value, E: Eval>(xs: &[Expr<T, E>], axis: isize) -> Expr<T, E> {
+    assert!(!xs.is_empty(), "Nothing to concatenate");
+    let axis = xs[0].shape().wrap(axis);
+    xs.iter()
+        .cloned()
+        .reduce(|l, r| {
+            assert_eq!(
+                l.shape().set(axis as isize, 0),
+                r.shape().set(axis as isize, 0),
+                "Can't concatenate along axis {axis}"
+            );
+            let shape = l.shape().set(
+                axis as isize,
+                l.shape()[axis as isize] + r.shape()[axis as isize],
+            );
+            Expr(ExprData::new(Cat { axis, shape, l, r }))
+        })
+        .unwrap()
+}
+
+/// Joins `xs`, which have to be of the same shape, along a new axis at `axis`
+pub fn stack<T: Value, E: Eval>(xs: &[Expr<T, E>], axis: usize) -> Expr<T, E> {
+    let xs: Vec<_> = xs.iter().map(|x| x.clone().broadcast(axis, 1)).collect();
+    cat(&xs, axis as isize)
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Splits into consecutive parts along `axis`, with `sizes` elements each
+    pub fn split(self, axis: isize, sizes: &[usize]) -> Vec<Expr<T, E>> {
+        assert_eq!(
+            sizes.iter().sum::<usize>(),
+            self.shape()[axis],
+            "Sizes {sizes:?} don't add up to axis {axis} of {:?}",
+            self.shape()
+        );
+        let mut start = 0;
+        sizes
+            .iter()
+            .map(|len| {
+                start += len;
+                self.clone().narrow(axis, start - len, *len)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,543 @@
+use crate::hl::expr::constant::constant;
+use crate::hl::expr::{Eval, Expr, Value, C128};
+use crate::ll::cpu::Cpu;
+use ndarray::ArrayD;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt::{Display, Formatter};
+
+/// Compares gradients from [`ExprImpl::backward`](crate::hl::expr::ExprImpl::backward) against
+/// central finite differences, both computed on the [`Cpu`] reference backend.
+///
+/// Non-scalar outputs are reduced with a fixed random cotangent, so every output element matters.
+///
+/// Complex inputs are perturbed along both parts and compared to gradients in the conjugate
+/// Wirtinger convention, `dL/dre + i dL/dim`.
+#[derive(Debug, Clone)]
+pub struct GradCheck {
+    /// Finite difference step
+    pub eps: f64,
+    pub atol: f64,
+    pub rtol: f64,
+}
+
+impl Default for GradCheck {
+    fn default() -> Self {
+        Self {
+            eps: 1e-6,
+            atol: 1e-5,
+            rtol: 1e-4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    /// Position of the input in the list passed to the check
+    pub input: usize,
+    /// Flat index of the element within the input
+    pub index: usize,
+    pub analytic: C128,
+    pub numeric: C128,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub mismatches: Vec<Mismatch>,
+    /// Largest absolute difference over all checked elements
+    pub max_err: f64,
+}
+
+impl Report {
+    pub fn ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} mismatched elements, max error {:e}",
+            self.mismatches.len(),
+            self.max_err
+        )?;
+        for m in &self.mismatches {
+            writeln!(
+                f,
+                "  input {} [{}]: analytic {}, numeric {}",
+                m.input, m.index, m.analytic, m.numeric
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl GradCheck {
+    /// Checks gradients of `y` with respect to each input, evaluated at the given values. Every
+    /// param `y` depends on must be listed.
+    pub fn run<T, X, E, D>(
+        &self,
+        e: &mut E,
+        y: &Expr<T, E>,
+        inputs: &[(&Expr<X, E>, ArrayD<D>)],
+    ) -> Report
+    where
+        T: Value,
+        X: Value,
+        E: Eval,
+        D: Copy + Into<C128>,
+    {
+        let mut rng = StdRng::seed_from_u64(0);
+        let ct: Vec<C128> = (0..y.shape().prod())
+            .map(|_| match T::DTYPE.is_complex() {
+                true => C128::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)),
+                false => C128::from(rng.gen_range(-1.0..1.0)),
+            })
+            .collect();
+
+        let grads: Vec<_> = inputs
+            .iter()
+            .map(|(x, _)| y.vjp(e, constant(y.shape().clone(), ct.clone()), *x))
+            .collect();
+
+        let ybuf = y.eval(e);
+        let gbufs: Vec<_> = grads.iter().map(|g| g.eval(e)).collect();
+        let xbufs: Vec<_> = inputs.iter().map(|(x, _)| x.eval(e)).collect();
+
+        let b = e.emitter();
+        let mut cpu = Cpu::new();
+        let values: Vec<ArrayD<C128>> = inputs.iter().map(|(_, v)| v.mapv(D::into)).collect();
+        for (xb, v) in xbufs.iter().zip(&values) {
+            cpu.set_complex(*xb, v.clone());
+        }
+
+        let mut report = Report::default();
+        for (i, (xb, v)) in xbufs.iter().zip(values).enumerate() {
+            let analytic = cpu.get_complex(b, gbufs[i]);
+            let mut v = v.clone();
+
+            for j in 0..v.len() {
+                let orig = v.as_slice().unwrap()[j];
+                let mut at = |x: C128, cpu: &mut Cpu| {
+                    v.as_slice_mut().unwrap()[j] = x;
+                    cpu.set_complex(*xb, v.clone());
+                    let out = cpu.get_complex(b, ybuf);
+                    out.iter()
+                        .zip(&ct)
+                        .map(|(o, c)| (c.conj() * o).re)
+                        .sum::<f64>()
+                };
+                let mut diff = |d: C128| {
+                    let n = (at(orig + d * self.eps, &mut cpu) - at(orig - d * self.eps, &mut cpu))
+                        / (2.0 * self.eps);
+                    at(orig, &mut cpu);
+                    n
+                };
+                let mut numeric = C128::from(diff(C128::new(1.0, 0.0)));
+                if X::DTYPE.is_complex() {
+                    numeric.im = diff(C128::i());
+                }
+
+                let analytic = analytic.as_slice().unwrap()[j];
+                let err = (analytic - numeric).norm();
+                report.max_err = report.max_err.max(err);
+                if err.is_nan() || err > self.atol + self.rtol * numeric.norm() {
+                    report.mismatches.push(Mismatch {
+                        input: i,
+                        index: j,
+                        analytic,
+                        numeric,
+                    });
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Runs [`GradCheck`] with default tolerances
+pub fn gradcheck<T, X, E, D>(
+    e: &mut E,
+    y: &Expr<T, E>,
+    inputs: &[(&Expr<X, E>, ArrayD<D>)],
+) -> Report
+where
+    T: Value,
+    X: Value,
+    E: Eval,
+    D: Copy + Into<C128>,
+{
+    GradCheck::default().run(e, y, inputs)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::cat::{cat, stack};
+    use crate::hl::expr::check::gradcheck;
+    use crate::hl::expr::constant::{constant, ones};
+    use crate::hl::expr::conv::ConvOpts;
+    use crate::hl::expr::custom::custom_op;
+    use crate::hl::expr::einsum::einsum;
+    use crate::hl::expr::embedding::embedding;
+    use crate::hl::expr::matmul::matmul;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::select::select;
+    use crate::hl::expr::{Expr, Value, C128};
+    use crate::hl::shape::Shape;
+    use crate::hl::test::TestEv;
+    use crate::ml::PadKind;
+    use crate::shape;
+    use ndarray::{arr1, ArrayD};
+    use num::traits::Inv;
+
+    // The Cpu backend rounds to the element type, so finite differences need doubles
+    type Ex = Expr<f64, TestEv<f64>>;
+
+    fn check1(f: impl Fn(Ex) -> Ex, x: &[f64]) {
+        let mut e = TestEv::with_grad();
+        let p: Ex = param(shape![x.len()]);
+        let y = f(p.clone());
+        let report = gradcheck(&mut e, &y, &[(&p, arr1(x).into_dyn())]);
+        assert!(report.ok(), "{report}");
+    }
+
+    fn check2(f: impl Fn(Ex, Ex) -> Ex, a: &[f64], b: &[f64]) {
+        let mut e = TestEv::with_grad();
+        let pa: Ex = param(shape![a.len()]);
+        let pb: Ex = param(shape![b.len()]);
+        let y = f(pa.clone(), pb.clone());
+        let inputs: [(&Ex, ArrayD<f64>); 2] =
+            [(&pa, arr1(a).into_dyn()), (&pb, arr1(b).into_dyn())];
+        let report = gradcheck(&mut e, &y, &inputs);
+        assert!(report.ok(), "{report}");
+    }
+
+    const A: [f64; 4] = [0.5, -1.5, 2.0, 0.25];
+    const B: [f64; 4] = [1.5, 0.75, -0.5, 3.0];
+    const POS: [f64; 4] = [0.5, 1.5, 2.0, 0.25];
+    const FRAC: [f64; 4] = [0.4, -1.2, 2.6, 0.7];
+
+    #[test]
+    fn test_bin() {
+        check2(|a, b| a + b, &A, &B);
+        check2(|a, b| a * b, &A, &B);
+        check2(|a, b| a - b, &A, &B);
+        check2(|a, b| a / b, &A, &B);
+        check1(|a| a.clone() * &a, &A);
+        check1(|a| a.clone() + &a, &A);
+        check2(|a, b| a.pow(b), &POS, &B);
+        check1(|a| a.powf(3.0), &A);
+        check2(|a, b| a.minimum(b), &A, &B);
+        check2(|a, b| a.maximum(b), &A, &B);
+        // Ties split the gradient, which finite differences see as the average slope
+        check1(|a| a.clone().maximum(&a) + a.clone().minimum(a), &A);
+    }
+
+    #[test]
+    fn test_un() {
+        check1(|a| -a, &A);
+        check1(|a| a.inv(), &A);
+        check1(|a| a.exp(), &A);
+        check1(|a| a.log(), &POS);
+        check1(|a| a.clone().gtz() * &a, &A);
+        check1(|a| a.sqrt(), &POS);
+        check1(|a| a.rsqrt(), &POS);
+        check1(|a| a.sin(), &A);
+        check1(|a| a.cos(), &A);
+        check1(|a| a.tanh(), &A);
+        check1(|a| a.abs(), &A);
+        check1(|a| a.clone().sign() * &a, &A);
+        // Away from the steps
+        check1(|a| a.floor(), &FRAC);
+        check1(|a| a.ceil(), &FRAC);
+        check1(|a| a.clone().round() + &a, &FRAC);
+        check1(|a| a.erf(), &A);
+        check1(|a| a.expm1(), &A);
+        check1(|a| a.log1p(), &POS);
+    }
+
+    #[test]
+    fn test_act() {
+        check1(|a| a.relu(), &A);
+        check1(|a| a.leaky_relu(0.1), &A);
+        check1(|a| a.elu(1.5), &A);
+        check1(|a| a.sigmoid(), &A);
+        check1(|a| a.silu(), &A);
+        check1(|a| a.softplus(), &[0.0, -30.0, 2.0, 30.0]);
+        check1(|a| a.mish(), &A);
+        check1(|a| a.gelu(), &A);
+        check1(|a| a.gelu_tanh(), &A);
+        check1(|a| a.hardtanh(-1.0, 1.0), &A);
+    }
+
+    #[test]
+    fn test_stats() {
+        check1(|a| a.mean(&[], false), &A);
+        check1(|a| a.broadcast(0, 2).mean(&[0], true), &A);
+        check1(|a| a.var(&[0], 1, false), &A);
+        check1(|a| a.std(&[], 0, false), &A);
+        check1(|a| a.logsumexp(&[0], false), &A);
+        check1(|a| a.broadcast(0, 3).logsumexp(&[-1], true), &A);
+        check1(|a| a.softmax(0), &A);
+        check1(|a| a.log_softmax(-1), &A);
+        check1(|a| (a.broadcast(1, 2) * b2()).softmax(0), &A);
+    }
+
+    // Mixes the rows, so softmax over the other axis isn't uniform
+    fn b2() -> Ex {
+        constant(shape![4, 2], vec![1.0, -1.0, 0.5, 2.0, -0.5, 0.0, 1.5, 3.0])
+    }
+
+    #[test]
+    fn test_select() {
+        check2(|a, b| select(a.clone().gt(&b), a, b), &A, &B);
+        check1(|a| a.clamp(-1.0, 1.0), &A);
+    }
+
+    #[test]
+    fn test_shape_ops() {
+        check1(|a| a.broadcast(0, 3), &A);
+        check1(|a| a.broadcast(0, 3).sum_axis(1), &A);
+        check1(|a| a.broadcast(1, 3).sum(&[0], true), &A);
+        check1(|a| a.broadcast(0, 2).max(&[1], false), &A);
+        check1(|a| a.broadcast(0, 2).min(&[-1], false), &A);
+        check1(|a| a.prod(&[], false), &A);
+        check1(|a| a.prod(&[0], false), &[0.5, 0.0, 2.0, 1.5]);
+        check1(|a| a.prod(&[0], false), &[0.0, 0.0, 2.0, 1.5]);
+
+        check_nd(|p| p[0].clone().reshape([4, 2]) * b2(), &[shape![8]]);
+        check_nd(|p| p[0].clone().transpose(0, -1), &[shape![2, 3, 4]]);
+        check_nd(
+            |p| p[0].clone().permute(&[1, 2, 0]) * &p[1],
+            &[shape![2, 3, 4], shape![3, 4, 2]],
+        );
+        check_nd(|p| p[0].clone().flatten().powf(2.0), &[shape![2, 3, 2]]);
+        check_nd(
+            |p| p[0].clone().flip(1).narrow(1, 1, 2).powf(2.0),
+            &[shape![2, 3, 2]],
+        );
+        check_nd(
+            |p| cat(p, 1).powf(2.0),
+            &[shape![2, 3, 2], shape![2, 1, 2], shape![2, 3, 2]],
+        );
+        check_nd(
+            |p| stack(p, 2).powf(2.0),
+            &[shape![2, 3, 2], shape![2, 3, 2]],
+        );
+        check_nd(
+            |p| {
+                let parts = p[0].clone().split(-1, &[1, 1]);
+                parts[0].clone() * &parts[1]
+            },
+            &[shape![2, 3, 2]],
+        );
+        for kind in [PadKind::Zero, PadKind::One, PadKind::Edge, PadKind::Mirror] {
+            check_nd(
+                |p| p[0].clone().pad(1, 2, 1, kind).powf(2.0),
+                &[shape![2, 3, 2]],
+            );
+            check_nd(
+                |p| p[0].clone().pad(-1, 0, 1, kind).powf(2.0),
+                &[shape![2, 3, 2]],
+            );
+        }
+    }
+
+    /// Checks `f` with a parameter of each of `shapes`, filled with distinct values
+    fn check_nd(f: impl Fn(&[Ex]) -> Ex, shapes: &[Shape]) {
+        let mut e = TestEv::with_grad();
+        let ps: Vec<Ex> = shapes.iter().map(|s| param(s.clone())).collect();
+        let y = f(&ps);
+        let inputs: Vec<(&Ex, ArrayD<f64>)> = ps
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let vals =
+                    (0..p.shape().prod()).map(|j| ((j * 7 + i * 3) % 11) as f64 / 4.0 - 1.25);
+                (
+                    p,
+                    ArrayD::from_shape_vec(&p.shape()[..], vals.collect()).unwrap(),
+                )
+            })
+            .collect();
+        let report = gradcheck(&mut e, &y, &inputs);
+        assert!(report.ok(), "{report}");
+    }
+
+    #[test]
+    fn test_matmul() {
+        let mm = |p: &[Ex]| matmul(&p[0], &p[1]);
+        check_nd(mm, &[shape![2, 3], shape![3, 4]]);
+        check_nd(mm, &[shape![2, 2, 3], shape![2, 3, 2]]);
+        check_nd(mm, &[shape![2, 2, 3], shape![3, 2]]);
+        check_nd(mm, &[shape![2, 1, 2, 3], shape![3, 3, 1]]);
+        check_c(|z| z.clone().broadcast(1, 2).matmul(z.broadcast(0, 2)));
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let bin = |f: fn(Ex, Ex) -> Ex| move |p: &[Ex]| f(p[0].clone(), p[1].clone());
+        check_nd(bin(|a, b| a * b), &[shape![2, 3], shape![1]]);
+        check_nd(bin(|a, b| a + b), &[shape![2, 1, 3], shape![4, 1]]);
+        check_nd(bin(|a, b| a - b), &[shape![3], shape![2, 3]]);
+        check_nd(bin(|a, b| a.maximum(b)), &[shape![2, 1], shape![1, 3]]);
+    }
+
+    #[test]
+    fn test_index() {
+        let x = || shape![4, 3];
+        let idx = || constant(shape![2, 2], vec![2.0, 0.0, 2.0, 3.0]);
+        let sq = |y: Ex| y.powf(2.0);
+        check_nd(|p| sq(p[0].clone().slice(0, 1.., 2)), &[x()]);
+        check_nd(|p| sq(p[0].clone().slice(1, ..=-2, 1)), &[x()]);
+        check_nd(|p| sq(p[0].clone().slice(0, -3..-1, 1)), &[x()]);
+        check_nd(|p| sq(p[0].clone().index(-1, 1)), &[x()]);
+        check_nd(|p| sq(p[0].clone().gather(0, idx())), &[x()]);
+        check_nd(
+            |p| sq(p[0].clone().gather(1, idx().narrow(0, 0, 1))),
+            &[x()],
+        );
+        check_nd(
+            |p| sq(p[0].clone().index_add(0, idx(), &p[1])),
+            &[x(), shape![2, 2, 3]],
+        );
+        let first = || constant(shape![2], vec![3.0, 1.0]);
+        check_nd(
+            |p| sq(p[0].clone().scatter(0, first(), &p[1])),
+            &[x(), shape![2, 3]],
+        );
+        check_nd(
+            |p| {
+                let mask = p[0].clone().gt(&p[1]);
+                sq(p[0].clone().masked_fill(mask, 2.0))
+            },
+            &[x(), shape![3]],
+        );
+        check_nd(
+            |p| sq(p[0].clone().compress(1, &[true, false, true])),
+            &[x()],
+        );
+        check_nd(|p| sq(embedding(&p[0], idx())), &[x()]);
+    }
+
+    #[test]
+    fn test_conv() {
+        let conv = |o: ConvOpts| move |p: &[Ex]| p[0].clone().conv(&p[1], Some(p[2].clone()), &o);
+        let opts = ConvOpts {
+            stride: 2,
+            padding: 1,
+            dilation: 2,
+            ..Default::default()
+        };
+        check_nd(conv(opts), &[shape![2, 2, 7], shape![3, 2, 2], shape![3]]);
+        let grouped = ConvOpts {
+            groups: 2,
+            padding: 1,
+            pad_kind: PadKind::Mirror,
+            ..Default::default()
+        };
+        check_nd(
+            conv(grouped),
+            &[shape![1, 4, 3, 3], shape![2, 2, 2, 2], shape![2]],
+        );
+        let cube = shape![1, 1, 2, 3, 2];
+        let w = shape![1, 1, 2, 2, 2];
+        check_nd(conv(ConvOpts::default()), &[cube.clone(), w, shape![1]]);
+
+        check_nd(
+            |p| p[0].clone().max_pool(&[2, 2], 1, 1),
+            &[shape![1, 2, 3, 3]],
+        );
+        check_nd(|p| p[0].clone().avg_pool(&[2], 2, 1), &[shape![2, 1, 5]]);
+        check_nd(|p| p[0].clone().adaptive_avg_pool(&[2, 2, 1]), &[cube]);
+    }
+
+    #[test]
+    fn test_einsum() {
+        let ein = |spec: &'static str| move |p: &[Ex]| einsum(spec, p);
+        check_nd(ein("bij,bjk->bik"), &[shape![2, 2, 3], shape![2, 3, 2]]);
+        check_nd(ein("ij,ik->kj"), &[shape![3, 2], shape![3, 4]]);
+        check_nd(ein("i,ijk,k"), &[shape![2], shape![2, 3, 2], shape![2]]);
+        check_nd(ein("ij,kl->jlik"), &[shape![2, 3], shape![2, 2]]);
+    }
+
+    #[test]
+    fn test_second_order() {
+        for f in [
+            |a: Ex| a.exp(),
+            |a: Ex| a.log(),
+            |a: Ex| a.inv(),
+            |a: Ex| a.clone() * &a * &a,
+            |a: Ex| a.sqrt(),
+            |a: Ex| a.rsqrt(),
+            |a: Ex| a.sin(),
+            |a: Ex| a.cos(),
+            |a: Ex| a.tanh(),
+            |a: Ex| a.erf(),
+            |a: Ex| a.log1p(),
+            |a: Ex| a.softmax(0),
+            |a: Ex| a.log_softmax(0),
+        ] {
+            let mut e = TestEv::with_grad();
+            let p: Ex = param(shape![4]);
+            let g = f(p.clone()).vjp(&mut e, ones(shape![4]), &p);
+            let report = gradcheck(&mut e, &g, &[(&p, arr1(&POS).into_dyn())]);
+            assert!(report.ok(), "{report}");
+        }
+    }
+
+    // Casts round to the target type, so f64 parts keep finite differences accurate
+    type Cx = Expr<C128, TestEv<C128>>;
+
+    fn check_c<T: Value>(f: impl Fn(Cx) -> Expr<T, TestEv<C128>>) {
+        let mut e = TestEv::with_grad();
+        let p: Cx = param(shape![3]);
+        let y = f(p.clone());
+        let z = [(0.5, -1.0), (-1.5, 0.25), (2.0, 0.75)].map(|(re, im)| C128::new(re, im));
+        let report = gradcheck(&mut e, &y, &[(&p, arr1(&z).into_dyn())]);
+        assert!(report.ok(), "{report}");
+    }
+
+    #[test]
+    fn test_complex() {
+        check_c(|z| z.clone() * &z);
+        check_c(|z| z.exp());
+        check_c(|z| z.log());
+        check_c(|z| z.inv());
+        check_c(|z| z.conj());
+        check_c(|z| z.clone().conj() * &z);
+        check_c(|z| z.abs());
+        check_c(|z| z.angle());
+        check_c(|z| z.real());
+        check_c(|z| z.imag());
+        check_c(|z| z.sqrt());
+        check_c(|z| z.sin());
+        check_c(|z| z.tanh());
+        check_c(|z| z.sign());
+        check_c(|z| z.clone().real() * z.imag());
+        check_c(|z| z.clone().abs() * z);
+    }
+
+    #[test]
+    fn test_abs_at_zero() {
+        // Central differences see the kink as flat, like the subgradient
+        let mut e = TestEv::with_grad();
+        let p: Cx = param(shape![2]);
+        let y = p.clone().abs();
+        let z = [C128::new(0.0, 0.0), C128::new(3.0, -4.0)];
+        let report = gradcheck(&mut e, &y, &[(&p, arr1(&z).into_dyn())]);
+        assert!(report.ok(), "{report}");
+        check1(|a| a.abs(), &[0.0, -1.5]);
+    }
+
+    #[test]
+    fn test_catches_wrong_rule() {
+        let mut e = TestEv::with_grad();
+        let p: Ex = param(shape![4]);
+        // Gradient of exp replaced by identity
+        let y = custom_op(p.clone(), |x| x.exp(), |_, g| g);
+        let report = gradcheck(&mut e, &y, &[(&p, arr1(&A).into_dyn())]);
+        assert_eq!(report.mismatches.len(), 4);
+    }
+}
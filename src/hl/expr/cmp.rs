@@ -0,0 +1,147 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::broadcast::broadcast_pair;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug, Clone, Copy)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+}
+
+#[derive(Debug)]
+/// Elementwise comparison, `true` where it holds. Complex values are ordered by their real part.
+///
+/// Piecewise constant, so gradients stop here.
+struct Cmp<T: Value, E: Eval> {
+    op: CmpOp,
+    shape: Shape,
+    l: Expr<T, E>,
+    r: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<bool, E> for Cmp<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    // Visitors are typed by the element type, so they can't continue into the inputs
+    fn accept(&self, v: &mut dyn Visitor<bool, E>) {}
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.l);
+        f(&self.r);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let l = self.l.eval(e);
+        let r = self.r.eval(e);
+        let op = match self.op {
+            CmpOp::Eq => OpType::Eq,
+            CmpOp::Ne => OpType::Ne,
+            CmpOp::Lt => OpType::Lt,
+            CmpOp::Le => OpType::Le,
+        };
+        e.emitter().emit(op, &self.shape, l, r)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        None
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<bool, E>> {
+        if b.get(&self.l).is_none() && b.get(&self.r).is_none() {
+            return None;
+        }
+        let (l, r) = (b.get_or_broadcast(&self.l), b.get_or_broadcast(&self.r));
+        Some(l.cmp(self.op, r))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {}
+}
+
+/// Elementwise comparisons, broadcasting like the arithmetic ops. Both sides need the same element
+/// type, compare `f32` with `i64` by casting one of them with [`Expr::astype`].
+impl<T: Value, E: Eval> Expr<T, E> {
+    fn cmp(self, op: CmpOp, rhs: Expr<T, E>) -> Expr<bool, E> {
+        let (l, r) = broadcast_pair(self, rhs);
+        let out: Expr<bool, E> = Expr(ExprData::new(Cmp {
+            op,
+            shape: l.shape().clone(),
+            l,
+            r,
+        }));
+        out.set_requires_grad(false);
+        out
+    }
+
+    pub fn eq(self, rhs: impl Into<Expr<T, E>>) -> Expr<bool, E> {
+        self.cmp(CmpOp::Eq, rhs.into())
+    }
+    pub fn ne(self, rhs: impl Into<Expr<T, E>>) -> Expr<bool, E> {
+        self.cmp(CmpOp::Ne, rhs.into())
+    }
+    pub fn lt(self, rhs: impl Into<Expr<T, E>>) -> Expr<bool, E> {
+        self.cmp(CmpOp::Lt, rhs.into())
+    }
+    pub fn le(self, rhs: impl Into<Expr<T, E>>) -> Expr<bool, E> {
+        self.cmp(CmpOp::Le, rhs.into())
+    }
+    pub fn gt(self, rhs: impl Into<Expr<T, E>>) -> Expr<bool, E> {
+        rhs.into().cmp(CmpOp::Lt, self)
+    }
+    pub fn ge(self, rhs: impl Into<Expr<T, E>>) -> Expr<bool, E> {
+        rhs.into().cmp(CmpOp::Le, self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::constant::constant;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::arr1;
+
+    #[test]
+    fn test_cmp() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let c: Expr<f32, TestEv> = constant(shape![3], vec![1.0, 2.0, 3.0]);
+
+        let masks = [
+            x.clone().eq(&c),
+            x.clone().ne(&c),
+            x.clone().lt(&c),
+            x.clone().le(&c),
+            x.clone().gt(&c),
+            x.clone().ge(&c),
+        ];
+        assert!(masks.iter().all(|m| !m.requires_grad()));
+
+        let masked = x.clone() * masks[4].clone();
+        masked.backward(&mut e);
+        assert!(x.grad().is_some());
+
+        let xb = x.eval(&mut e);
+        let bufs: Vec<_> = masks.iter().map(|m| m.eval(&mut e)).collect();
+        let mut cpu = Cpu::new();
+        cpu.set(xb, arr1(&[0.0, 2.0, 4.0]).into_dyn());
+        let expected = [
+            [0.0, 1.0, 0.0],
+            [1.0, 0.0, 1.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 1.0, 1.0],
+        ];
+        for (b, v) in bufs.iter().zip(expected) {
+            assert_eq!(cpu.get(e.emitter(), *b), arr1(&v).into_dyn());
+        }
+    }
+}
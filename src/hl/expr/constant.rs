@@ -0,0 +1,76 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor, C128};
+use crate::hl::shape::Shape;
+use crate::ml::BufId;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+
+/// Tensor with values known while building the graph.
+pub struct Const<T, E> {
+    shape: Shape,
+    /// Either a single value splatted over the whole shape, or one value per element
+    data: Vec<C128>,
+    _p: PhantomData<(T, E)>,
+}
+
+impl<T, E> Debug for Const<T, E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "const{:?}", self.data)
+    }
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Const<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {}
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {}
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        e.emitter()
+            .constant(self.shape.clone(), T::DTYPE, self.data.clone())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        None
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        None
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {}
+}
+
+/// Values are rounded to `T`, imaginary parts are dropped for real `T`
+pub fn constant<T, E, D>(shape: Shape, data: Vec<D>) -> Expr<T, E>
+where
+    T: Value,
+    E: Eval,
+    D: Into<C128>,
+{
+    assert!(
+        data.len() == 1 || data.len() == shape.prod(),
+        "Constant of shape {shape:?} can't hold {} values",
+        data.len()
+    );
+    Expr(ExprData::new(Const {
+        shape,
+        data: data.into_iter().map(|v| T::from_c128(v.into()).to_c128()).collect(),
+        _p: Default::default(),
+    }))
+}
+
+pub fn full<T: Value, E: Eval>(shape: Shape, v: impl Into<C128>) -> Expr<T, E> {
+    constant(shape, vec![v])
+}
+
+pub fn zeros<T: Value, E: Eval>(shape: Shape) -> Expr<T, E> {
+    full(shape, 0.0)
+}
+
+pub fn ones<T: Value, E: Eval>(shape: Shape) -> Expr<T, E> {
+    full(shape, 1.0)
+}
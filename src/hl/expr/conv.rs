@@ -0,0 +1,265 @@
+use crate::hl::expr::cat::{cat, stack};
+use crate::hl::expr::{Eval, Expr, Value};
+use crate::hl::shape::Shape;
+use crate::ml::PadKind;
+
+/// Options of a convolution, the same for every spatial axis
+#[derive(Debug, Clone)]
+pub struct ConvOpts {
+    pub stride: usize,
+    /// Elements added at both ends of each spatial axis
+    pub padding: usize,
+    pub pad_kind: PadKind,
+    /// Distance between kernel elements
+    pub dilation: usize,
+    /// Channels are split into `groups` convolved separately
+    pub groups: usize,
+}
+
+impl Default for ConvOpts {
+    fn default() -> Self {
+        Self {
+            stride: 1,
+            padding: 0,
+            pad_kind: PadKind::Zero,
+            dilation: 1,
+            groups: 1,
+        }
+    }
+}
+
+/// Number of windows along an axis of `n` elements, padding included
+fn windows_along(n: usize, kernel: usize, stride: usize, dilation: usize) -> usize {
+    let span = dilation * (kernel - 1) + 1;
+    assert!(n >= span, "Kernel spanning {span} doesn't fit {n} elements");
+    (n - span) / stride + 1
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Pads all axes from 2 on, the spatial axes of `[N, C, ..]` tensors
+    fn pad_spatial(self, padding: usize, kind: PadKind) -> Expr<T, E> {
+        let ndim = self.shape()[..].len();
+        (2..ndim).fold(self, |x, a| x.pad(a as isize, padding, padding, kind))
+    }
+
+    /// Windows of `kernel` elements over the spatial axes of `[N, C, ..]`, stacked into
+    /// `[N, C, K, ..]` with `K` the number of kernel elements
+    fn windows(self, kernel: &[usize], stride: usize, dilation: usize) -> Expr<T, E> {
+        let spatial = &self.shape()[2..];
+        assert_eq!(
+            spatial.len(),
+            kernel.len(),
+            "Kernel {kernel:?} doesn't match spatial axes of {:?}",
+            self.shape()
+        );
+        let out: Vec<usize> = spatial
+            .iter()
+            .zip(kernel)
+            .map(|(n, k)| windows_along(*n, *k, stride, dilation))
+            .collect();
+
+        // One strided slice per kernel element, in row-major order of the kernel
+        let count = kernel.iter().product::<usize>();
+        let parts: Vec<Expr<T, E>> = (0..count)
+            .map(|mut i| {
+                let mut x = self.clone();
+                for a in (0..kernel.len()).rev() {
+                    let start = (i % kernel[a]) * dilation;
+                    let end = start + (out[a] - 1) * stride + 1;
+                    x = x.slice(a as isize + 2, start as isize..end as isize, stride);
+                    i /= kernel[a];
+                }
+                x
+            })
+            .collect();
+        stack(&parts, 2)
+    }
+
+    /// Convolution of `[N, C, ..]` with a `[O, C / groups, ..]` kernel and an optional `[O]` bias,
+    /// giving `[N, O, ..]`. Works on any number of spatial axes, lowered to a matrix product over
+    /// the windows of the input.
+    pub fn conv(
+        self,
+        w: impl Into<Expr<T, E>>,
+        bias: Option<Expr<T, E>>,
+        o: &ConvOpts,
+    ) -> Expr<T, E> {
+        let w = w.into();
+        let (xs, ws) = (self.shape().clone(), w.shape().clone());
+        assert_eq!(
+            xs[..].len(),
+            ws[..].len(),
+            "Input {xs:?} and kernel {ws:?} differ in rank"
+        );
+        let (n, c, oc, g) = (xs[0], xs[1], ws[0], o.groups);
+        assert!(
+            c % g == 0 && oc % g == 0,
+            "{c} input and {oc} output channels don't split into {g} groups"
+        );
+        assert_eq!(ws[1] * g, c, "Kernel {ws:?} doesn't match {c} channels");
+
+        let cols = self
+            .pad_spatial(o.padding, o.pad_kind)
+            .windows(&ws[2..], o.stride, o.dilation);
+        let out = cols.shape()[3..].to_vec();
+        let per_group = ws[1..].iter().product::<usize>();
+        let len = out.iter().product::<usize>();
+
+        // [G, O / G, C / G * K] @ [N, G, C / G * K, L] -> [N, G, O / G, L]
+        let cols = cols.reshape([n, g, per_group, len]);
+        let w = w.reshape([g, oc / g, per_group]);
+        let y = w.matmul(cols).reshape([&[n, oc][..], &out].concat());
+        match bias {
+            Some(b) => {
+                let shape = Shape::from([&[oc][..], &vec![1; out.len()]].concat());
+                y + b.reshape(shape)
+            }
+            None => y,
+        }
+    }
+
+    /// Convolution of `[N, C, L]`, see [`Expr::conv`]
+    pub fn conv1d(
+        self,
+        w: impl Into<Expr<T, E>>,
+        bias: Option<Expr<T, E>>,
+        o: &ConvOpts,
+    ) -> Expr<T, E> {
+        assert_eq!(self.shape()[..].len(), 3, "conv1d takes [N, C, L] inputs");
+        self.conv(w, bias, o)
+    }
+
+    /// Convolution of `[N, C, H, W]`, see [`Expr::conv`]
+    pub fn conv2d(
+        self,
+        w: impl Into<Expr<T, E>>,
+        bias: Option<Expr<T, E>>,
+        o: &ConvOpts,
+    ) -> Expr<T, E> {
+        assert_eq!(
+            self.shape()[..].len(),
+            4,
+            "conv2d takes [N, C, H, W] inputs"
+        );
+        self.conv(w, bias, o)
+    }
+
+    /// Convolution of `[N, C, D, H, W]`, see [`Expr::conv`]
+    pub fn conv3d(
+        self,
+        w: impl Into<Expr<T, E>>,
+        bias: Option<Expr<T, E>>,
+        o: &ConvOpts,
+    ) -> Expr<T, E> {
+        assert_eq!(
+            self.shape()[..].len(),
+            5,
+            "conv3d takes [N, C, D, H, W] inputs"
+        );
+        self.conv(w, bias, o)
+    }
+
+    /// Maximum over windows of `kernel` on the spatial axes of `[N, C, ..]`. Padding repeats the
+    /// edges, which never changes the maximum of a window.
+    pub fn max_pool(self, kernel: &[usize], stride: usize, padding: usize) -> Expr<T, E> {
+        assert!(
+            kernel.iter().all(|k| padding < *k),
+            "Padding {padding} covers whole windows of {kernel:?}"
+        );
+        self.pad_spatial(padding, PadKind::Edge)
+            .windows(kernel, stride, 1)
+            .max(&[2], false)
+    }
+
+    /// Mean over windows of `kernel` on the spatial axes of `[N, C, ..]`. Padded zeros count
+    /// towards the mean.
+    pub fn avg_pool(self, kernel: &[usize], stride: usize, padding: usize) -> Expr<T, E> {
+        self.pad_spatial(padding, PadKind::Zero)
+            .windows(kernel, stride, 1)
+            .mean(&[2], false)
+    }
+
+    /// Mean over `out` windows along each spatial axis of `[N, C, ..]`. Window `i` of an axis of `n`
+    /// elements covers `floor(i * n / out)..ceil((i + 1) * n / out)`.
+    pub fn adaptive_avg_pool(self, out: &[usize]) -> Expr<T, E> {
+        assert_eq!(
+            self.shape()[2..].len(),
+            out.len(),
+            "Output {out:?} doesn't match spatial axes of {:?}",
+            self.shape()
+        );
+        // Means of rectangular windows are means along one axis after another
+        out.iter().enumerate().fold(self, |x, (a, m)| {
+            let (axis, n) = (a as isize + 2, x.shape()[a as isize + 2]);
+            let parts: Vec<Expr<T, E>> = (0..*m)
+                .map(|i| {
+                    let (start, end) = (i * n / m, ((i + 1) * n).div_ceil(*m));
+                    x.clone()
+                        .narrow(axis, start, end - start)
+                        .mean(&[axis], true)
+                })
+                .collect();
+            cat(&parts, axis)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::conv::ConvOpts;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::{arr1, Array};
+
+    #[test]
+    fn test_conv() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![1, 1, 3, 3]);
+        let w: Expr<f32, TestEv> = param(shape![2, 1, 2, 2]);
+        let b: Expr<f32, TestEv> = param(shape![2]);
+        let y = x
+            .clone()
+            .conv2d(w.clone(), Some(b.clone()), &ConvOpts::default());
+        assert_eq!(y.shape(), &shape![1, 2, 2, 2]);
+        let opts = ConvOpts {
+            stride: 2,
+            padding: 1,
+            ..Default::default()
+        };
+        let strided = x.clone().conv2d(w.clone(), None, &opts);
+        assert_eq!(strided.shape(), &shape![1, 2, 2, 2]);
+        let pooled = x.clone().max_pool(&[2, 2], 1, 0);
+        let avg = x.clone().adaptive_avg_pool(&[2, 1]);
+
+        let (xb, wb, bb) = (x.eval(&mut e), w.eval(&mut e), b.eval(&mut e));
+        let outs = [y, strided, pooled, avg].map(|y| y.eval(&mut e));
+        let mut cpu = Cpu::new();
+        let grid = |n: usize, s: &[usize]| {
+            Array::range(1.0, n as f64 + 1.0, 1.0)
+                .into_shape(s)
+                .unwrap()
+        };
+        cpu.set(xb, grid(9, &[1, 1, 3, 3]));
+        // Sums the window and picks its top left element
+        cpu.set(
+            wb,
+            arr1(&[1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0])
+                .into_shape(vec![2, 1, 2, 2])
+                .unwrap(),
+        );
+        cpu.set(bb, arr1(&[0.5, 0.0]).into_dyn());
+
+        let bld = e.emitter();
+        let mut flat = |i: usize| cpu.get(bld, outs[i]).iter().copied().collect::<Vec<f64>>();
+        assert_eq!(flat(0), [12.5, 16.5, 24.5, 28.5, 1.0, 2.0, 4.0, 5.0]);
+        // Windows at -1 and 1 with a zero border
+        assert_eq!(flat(1), [1.0, 5.0, 11.0, 28.0, 0.0, 0.0, 0.0, 5.0]);
+        assert_eq!(flat(2), [5.0, 6.0, 8.0, 9.0]);
+        // Scales are constants of the element type, so only f32 accurate
+        let avg = flat(3);
+        assert!((avg[0] - 3.5).abs() < 1e-6 && (avg[1] - 6.5).abs() < 1e-6);
+    }
+}
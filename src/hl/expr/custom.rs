@@ -0,0 +1,277 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::zeros;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::BufId;
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+
+type Backward<T, E> =
+    dyn Fn(&[Expr<T, E>], Expr<<E as Eval>::Grad, E>) -> Vec<Expr<<E as Eval>::Grad, E>>;
+type Jvp<T, E> = dyn Fn(&[Expr<T, E>], &[Expr<<E as Eval>::Grad, E>]) -> Expr<<E as Eval>::Grad, E>;
+
+/// Op with a user provided gradient. Evaluates `out`, but the backwards pass skips its graph and
+/// calls `bwd` instead. The forward mode pass calls `jvp` if given, otherwise it goes through the
+/// graph of `out`.
+pub struct Custom<T: Value, E: Eval> {
+    xs: Vec<Expr<T, E>>,
+    out: Expr<T, E>,
+    bwd: Rc<Backward<T, E>>,
+    jvp: Option<Rc<Jvp<T, E>>>,
+}
+
+impl<T: Value, E: Eval> Debug for Custom<T, E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Custom")
+            .field("xs", &self.xs)
+            .field("out", &self.out)
+            .finish()
+    }
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Custom<T, E> {
+    fn shape(&self) -> &Shape {
+        self.out.shape()
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        for x in &self.xs {
+            x.accept(v);
+        }
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        for x in &self.xs {
+            f(x);
+        }
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        self.out.eval(e)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        if self.xs.iter().all(|x| x.tangent().is_none()) {
+            return None;
+        }
+        let Some(jvp) = &self.jvp else {
+            return Some(self.out.jvp(e));
+        };
+        let ts: Vec<_> = self
+            .xs
+            .iter()
+            .map(|x| x.tangent().unwrap_or_else(|| zeros(x.shape().clone())))
+            .collect();
+        Some(jvp(&self.xs, &ts))
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        if self.xs.iter().all(|x| b.get(x).is_none()) {
+            return None;
+        }
+        Some(Expr(ExprData::new(Custom {
+            xs: self.xs.iter().map(|x| b.get_or_broadcast(x)).collect(),
+            out: b.get_or_broadcast(&self.out),
+            bwd: self.bwd.clone(),
+            jvp: self.jvp.clone(),
+        })))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        let grads = (self.bwd)(&self.xs, grad);
+        assert_eq!(
+            grads.len(),
+            self.xs.len(),
+            "Custom op backward returned {} gradients for {} inputs",
+            grads.len(),
+            self.xs.len()
+        );
+        for (x, g) in self.xs.iter().zip(grads) {
+            x.accumulate(e, || g);
+        }
+    }
+}
+
+/// Whether `out` reaches a tensor that requires grad other than through `xs`
+fn hidden_input<E: Eval>(out: &dyn Node<E>, xs: &HashSet<usize>) -> bool {
+    fn visit<E: Eval>(n: &dyn Node<E>, xs: &HashSet<usize>, seen: &mut HashSet<usize>) -> bool {
+        if xs.contains(&n.key()) || !seen.insert(n.key()) {
+            return false;
+        }
+        let (mut leaf, mut found) = (true, false);
+        n.inputs(&mut |i| {
+            leaf = false;
+            found |= visit(i, xs, seen);
+        });
+        found || leaf && n.requires_grad()
+    }
+
+    visit(out, xs, &mut HashSet::new())
+}
+
+fn custom<T: Value, E: Eval>(
+    xs: Vec<Expr<T, E>>,
+    out: Expr<T, E>,
+    bwd: Rc<Backward<T, E>>,
+    jvp: Option<Rc<Jvp<T, E>>>,
+) -> Expr<T, E> {
+    let keys = xs.iter().map(|x| x.key()).collect();
+    assert!(
+        !hidden_input(&out, &keys),
+        "Custom op forward depends on tensors that require grad but aren't among its inputs"
+    );
+    Expr(ExprData::new(Custom { xs, out, bwd, jvp }))
+}
+
+/// Differentiable op defined outside the crate. `forward` builds the result from `x`, `backward`
+/// maps the gradient of the result and `x` to the gradient of `x`. Tangents in forward mode go
+/// through the graph of `forward`, see [`custom_op_with_jvp`] to change them as well.
+///
+/// Straight-through estimator: `custom_op(x, |x| x.round(), |_, g| g)`
+///
+/// Gradient reversal: `custom_op(x, |x| x, |_, g| -g)`
+///
+/// `forward` may only read `x` and tensors that don't require grad, see [`custom_op_n`] for ops
+/// with trainable inputs. Under [`vmap`](crate::hl::expr::batch::vmap) `backward` receives batched
+/// gradients.
+pub fn custom_op<T, E, F, B>(x: Expr<T, E>, forward: F, backward: B) -> Expr<T, E>
+where
+    T: Value,
+    E: Eval,
+    F: FnOnce(Expr<T, E>) -> Expr<T, E>,
+    B: Fn(&Expr<T, E>, Expr<E::Grad, E>) -> Expr<E::Grad, E> + 'static,
+{
+    let bwd = move |xs: &[Expr<T, E>], g| vec![backward(&xs[0], g)];
+    custom(vec![x.clone()], forward(x), Rc::new(bwd), None)
+}
+
+/// [`custom_op`] that also replaces the forward mode rule. `jvp` maps `x` and its tangent to the
+/// tangent of the result.
+pub fn custom_op_with_jvp<T, E, F, B, J>(
+    x: Expr<T, E>,
+    forward: F,
+    backward: B,
+    jvp: J,
+) -> Expr<T, E>
+where
+    T: Value,
+    E: Eval,
+    F: FnOnce(Expr<T, E>) -> Expr<T, E>,
+    B: Fn(&Expr<T, E>, Expr<E::Grad, E>) -> Expr<E::Grad, E> + 'static,
+    J: Fn(&Expr<T, E>, Expr<E::Grad, E>) -> Expr<E::Grad, E> + 'static,
+{
+    let bwd = move |xs: &[Expr<T, E>], g| vec![backward(&xs[0], g)];
+    let jvp = move |xs: &[Expr<T, E>], ts: &[Expr<E::Grad, E>]| jvp(&xs[0], ts[0].clone());
+    custom(
+        vec![x.clone()],
+        forward(x),
+        Rc::new(bwd),
+        Some(Rc::new(jvp)),
+    )
+}
+
+/// [`custom_op`] with several inputs. `backward` returns one gradient per input, in the order of
+/// `xs`.
+///
+/// Panics if `forward` reads a tensor that requires grad but isn't in `xs`, since it would get no
+/// gradient.
+pub fn custom_op_n<T, E, F, B>(xs: &[Expr<T, E>], forward: F, backward: B) -> Expr<T, E>
+where
+    T: Value,
+    E: Eval,
+    F: FnOnce(&[Expr<T, E>]) -> Expr<T, E>,
+    B: Fn(&[Expr<T, E>], Expr<E::Grad, E>) -> Vec<Expr<E::Grad, E>> + 'static,
+{
+    custom(xs.to_vec(), forward(xs), Rc::new(backward), None)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::constant::ones;
+    use crate::hl::expr::custom::{custom_op, custom_op_n, custom_op_with_jvp};
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::Expr;
+    use crate::hl::test::{run, TestEv};
+    use crate::shape;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_straight_through() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let y = custom_op(x.clone(), |x| x.gtz(), |_, g| g);
+        y.backward(&mut e);
+
+        // The gradient passes through unchanged, instead of being zeroed by `gtz`
+        assert!(Rc::ptr_eq(&x.grad().unwrap().0, &y.grad().unwrap().0));
+    }
+
+    #[test]
+    fn test_gradient_reversal() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let w: Expr<f32, TestEv> = param(shape![3]);
+        let y = custom_op(x.clone(), |x| x, |_, g| -g) * &w;
+        y.backward(&mut e);
+
+        let (xv, wv) = ([1.0, -2.0, 3.0], [4.0, 5.0, -6.0]);
+        let out = run(
+            &mut e,
+            &[(&x, &xv), (&w, &wv)],
+            &[y, x.grad().unwrap(), w.grad().unwrap()],
+        );
+        assert_eq!(out[0], [4.0, -10.0, -18.0]);
+        assert_eq!(out[1], [-4.0, -5.0, 6.0]);
+        assert_eq!(out[2], xv);
+    }
+
+    #[test]
+    fn test_custom_jvp() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+
+        // Forward and reverse mode both use the straight-through rule
+        let y = custom_op_with_jvp(x.clone(), |x| x.gtz(), |_, g| g, |_, t| t);
+        let g = y.vjp(&mut e, ones(shape![3]), &x);
+        x.set_tangent(ones(shape![3]));
+        let t = y.jvp(&mut e);
+        let out = run(&mut e, &[(&x, &[-1.0, 0.0, 2.0])], &[t, g]);
+        assert_eq!(out[0], [1.0; 3]);
+        assert_eq!(out[0], out[1]);
+
+        // Without a rule, tangents go through `gtz`
+        let y = custom_op(x.clone(), |x| x.gtz(), |_, g| g);
+        let t = y.jvp(&mut e);
+        let out = run(&mut e, &[(&x, &[-1.0, 0.0, 2.0])], &[t]);
+        assert_eq!(out[0], [0.0; 3]);
+    }
+
+    #[test]
+    fn test_custom_n() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![2]);
+        let w: Expr<f32, TestEv> = param(shape![2]);
+        let y = custom_op_n(
+            &[x.clone(), w.clone()],
+            |p| p[0].clone() * &p[1],
+            |p, g| vec![g.clone() * &p[1], g * &p[0]],
+        );
+        y.backward(&mut e);
+
+        let out = run(
+            &mut e,
+            &[(&x, &[2.0, 3.0]), (&w, &[-1.0, 4.0])],
+            &[x.grad().unwrap(), w.grad().unwrap()],
+        );
+        assert_eq!(out, [[-1.0, 4.0], [2.0, 3.0]]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hidden_input() {
+        let x: Expr<f32, TestEv> = param(shape![2]);
+        let w: Expr<f32, TestEv> = param(shape![2]);
+        custom_op(x, |x| x * &w, |_, g| g);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::hl::expr::constant::{constant, zeros};
+use crate::hl::expr::{topo, Eval, Expr, Grads, Node, Value};
+use crate::hl::module::Module;
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Vector-Jacobian product, gradient with respect to `x` of this expression seeded with `seed`.
+    ///
+    /// Runs on empty gradients and puts back the ones accumulated in this graph before, so earlier
+    /// backwards passes are unaffected.
+    pub fn vjp<V: Value>(
+        &self,
+        e: &mut E,
+        seed: Expr<E::Grad, E>,
+        x: &Expr<V, E>,
+    ) -> Expr<E::Grad, E> {
+        let nodes = topo::<E>(self);
+        let saved: Vec<Grads<E>> = nodes
+            .iter()
+            .map(|n| n.swap_grads(Default::default()))
+            .collect();
+
+        self.backward_with(e, seed);
+
+        // `x` outside of the graph keeps its own gradient
+        let reached = nodes.iter().any(|n| n.key() == Node::key(x));
+        let g = if reached { x.grad() } else { None };
+        for (n, s) in nodes.iter().zip(saved) {
+            n.swap_grads(s);
+        }
+        g.unwrap_or_else(|| zeros(x.shape().clone()))
+    }
+
+    /// Full Jacobian with respect to `x`. Has one row per element of this expression, each row having
+    /// the shape of `x`.
+    pub fn jacobian<V: Value>(&self, e: &mut E, x: &Expr<V, E>) -> Vec<Expr<E::Grad, E>> {
+        let n = self.shape().prod();
+        (0..n)
+            .map(|i| {
+                let mut hot = vec![0.0; n];
+                hot[i] = 1.0;
+                self.vjp(e, constant(self.shape().clone(), hot), x)
+            })
+            .collect()
+    }
+
+    /// Hessian of this single element expression with respect to `x`. Has one row per element of `x`.
+    pub fn hessian<V: Value>(&self, e: &mut E, x: &Expr<V, E>) -> Vec<Expr<E::Grad, E>> {
+        self.grad_scalar(e, x).jacobian(e, x)
+    }
+
+    /// Hessian-vector product of this single element expression, `v` has the shape of `x`.
+    pub fn hvp<V: Value>(
+        &self,
+        e: &mut E,
+        x: &Expr<V, E>,
+        v: Expr<E::Grad, E>,
+    ) -> Expr<E::Grad, E> {
+        self.grad_scalar(e, x).vjp(e, v, x)
+    }
+
+    fn grad_scalar<V: Value>(&self, e: &mut E, x: &Expr<V, E>) -> Expr<E::Grad, E> {
+        assert_eq!(
+            self.shape().prod(),
+            1,
+            "Expected a single element expression, got shape {:?}",
+            self.shape()
+        );
+        self.vjp(e, constant(self.shape().clone(), vec![1.0]), x)
+    }
+}
+
+/// Jacobian of `f` at `x`, see [`Expr::jacobian`]
+pub fn jacobian<T, E, M>(e: &mut E, f: &M, x: &Expr<T, E>) -> Vec<Expr<E::Grad, E>>
+where
+    T: Value,
+    E: Eval,
+    M: Module<Expr<T, E>, Output = Expr<T, E>>,
+{
+    f.forward(x.clone()).jacobian(e, x)
+}
+
+/// Hessian of `f` at `x`, see [`Expr::hessian`]
+pub fn hessian<T, E, M>(e: &mut E, f: &M, x: &Expr<T, E>) -> Vec<Expr<E::Grad, E>>
+where
+    T: Value,
+    E: Eval,
+    M: Module<Expr<T, E>, Output = Expr<T, E>>,
+{
+    f.forward(x.clone()).hessian(e, x)
+}
+
+/// Hessian of `f` at `x` multiplied by `v`, see [`Expr::hvp`]
+pub fn hvp<T, E, M>(e: &mut E, f: &M, x: &Expr<T, E>, v: Expr<E::Grad, E>) -> Expr<E::Grad, E>
+where
+    T: Value,
+    E: Eval,
+    M: Module<Expr<T, E>, Output = Expr<T, E>>,
+{
+    f.forward(x.clone()).hvp(e, x, v)
+}
+
+/// Gradient, tangent or cotangent expression in a graph evaluated by `E`
+pub type GradExpr<E> = Expr<<E as Eval>::Grad, E>;
+
+/// Output of a function along with a derivative, returned by the transformed functions below
+pub type WithGrad<T, E> = (Expr<T, E>, GradExpr<E>);
+
+/// Transforms `f` into a function returning its gradient at a point. `f` must produce a single
+/// element expression.
+pub fn grad<T, E, M>(f: M) -> impl Fn(&mut E, Expr<T, E>) -> Expr<E::Grad, E>
+where
+    T: Value,
+    E: Eval,
+    M: Module<Expr<T, E>, Output = Expr<T, E>>,
+{
+    let f = value_and_grad(f);
+    move |e: &mut E, x: Expr<T, E>| f(e, x).1
+}
+
+/// Like [`grad`], but the new function also returns the output of `f`
+pub fn value_and_grad<T, E, M>(f: M) -> impl Fn(&mut E, Expr<T, E>) -> WithGrad<T, E>
+where
+    T: Value,
+    E: Eval,
+    M: Module<Expr<T, E>, Output = Expr<T, E>>,
+{
+    move |e: &mut E, x: Expr<T, E>| {
+        let y = f.forward(x.clone());
+        let g = y.grad_scalar(e, &x);
+        (y, g)
+    }
+}
+
+/// Transforms `f` into a function of a point and a cotangent of the output. The new function returns
+/// the output of `f` and the vector-Jacobian product at that point.
+pub fn vjp<T, E, M>(f: M) -> impl Fn(&mut E, Expr<T, E>, GradExpr<E>) -> WithGrad<T, E>
+where
+    T: Value,
+    E: Eval,
+    M: Module<Expr<T, E>, Output = Expr<T, E>>,
+{
+    move |e: &mut E, x: Expr<T, E>, ct: Expr<E::Grad, E>| {
+        let y = f.forward(x.clone());
+        let g = y.vjp(e, ct, &x);
+        (y, g)
+    }
+}
+
+/// Transforms `f` into a function of a point and a tangent of the input. The new function returns
+/// the output of `f` and the Jacobian-vector product at that point.
+pub fn jvp<T, E, M>(f: M) -> impl Fn(&mut E, Expr<T, E>, GradExpr<E>) -> WithGrad<T, E>
+where
+    T: Value,
+    E: Eval,
+    M: Module<Expr<T, E>, Output = Expr<T, E>>,
+{
+    move |e: &mut E, x: Expr<T, E>, t: Expr<E::Grad, E>| {
+        let y = f.forward(x.clone());
+        y.zero_tangent();
+        x.set_tangent(t);
+
+        let t = y.jvp(e);
+        y.zero_tangent();
+        (y, t)
+    }
+}
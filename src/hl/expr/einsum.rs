@@ -0,0 +1,254 @@
+use crate::hl::expr::{Eval, Expr, Value};
+use std::collections::HashMap;
+
+/// Subscripts of an einsum, one label per axis of each operand and of the output
+#[derive(Debug, PartialEq)]
+struct Spec {
+    inputs: Vec<Vec<char>>,
+    output: Vec<char>,
+}
+
+impl Spec {
+    /// Parses `"ij,jk->ik"`. Without `->` the output holds the labels used only once, sorted like
+    /// in NumPy.
+    fn parse(spec: &str) -> Spec {
+        let labels = |s: &str| -> Vec<char> {
+            let l: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+            assert!(
+                l.iter().all(|c| c.is_ascii_alphabetic()),
+                "Einsum labels have to be letters, got {s:?}"
+            );
+            l
+        };
+        let (ins, out) = match spec.split_once("->") {
+            Some((ins, out)) => (ins, Some(labels(out))),
+            None => (spec, None),
+        };
+        let inputs: Vec<Vec<char>> = ins.split(',').map(labels).collect();
+        for l in &inputs {
+            assert!(
+                (1..l.len()).all(|i| !l[..i].contains(&l[i])),
+                "Repeated labels within an operand are not supported, got {spec:?}"
+            );
+        }
+
+        let count = |c: &char| inputs.iter().flatten().filter(|l| *l == c).count();
+        let output = out.unwrap_or_else(|| {
+            let mut once: Vec<char> = inputs
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|c| count(c) == 1)
+                .collect();
+            once.sort();
+            once
+        });
+        for (i, c) in output.iter().enumerate() {
+            assert!(
+                count(c) > 0,
+                "Output label {c:?} is not in any operand of {spec:?}"
+            );
+            assert!(
+                !output[..i].contains(c),
+                "Repeated output label {c:?} in {spec:?}"
+            );
+        }
+        Spec { inputs, output }
+    }
+}
+
+/// Permutes the axes of `x`, labeled by `labels`, into the order of `order`. Labels missing from
+/// `x` are broadcast in.
+fn align<T: Value, E: Eval>(
+    x: Expr<T, E>,
+    labels: &[char],
+    order: &[char],
+    sizes: &HashMap<char, usize>,
+) -> Expr<T, E> {
+    let pos = |c: &char| labels.iter().position(|l| l == c);
+    let axes: Vec<usize> = order.iter().filter_map(pos).collect();
+    let mut y = x.permute(&axes);
+    for (i, c) in order.iter().enumerate() {
+        if pos(c).is_none() {
+            y = y.broadcast(i, sizes[c]);
+        }
+    }
+    y
+}
+
+/// Sums away the axes of `x` whose labels are not in `keep`
+fn sum_except<T: Value, E: Eval>(
+    x: Expr<T, E>,
+    labels: &[char],
+    keep: impl Fn(&char) -> bool,
+) -> (Expr<T, E>, Vec<char>) {
+    let mut y = x;
+    for (i, c) in labels.iter().enumerate().rev() {
+        if !keep(c) {
+            y = y.sum_axis(i);
+        }
+    }
+    (y, labels.iter().copied().filter(keep).collect())
+}
+
+/// Einstein summation, `einsum("bij,bjk->bik", &[a, b])` is a batched matrix product. Labels
+/// missing from the output are summed over. Lowered to permutes, broadcasts, products and sums, two
+/// operands contracting over one label use a matrix product.
+pub fn einsum<T: Value, E: Eval>(spec: &str, xs: &[Expr<T, E>]) -> Expr<T, E> {
+    let Spec { inputs, output } = Spec::parse(spec);
+    assert_eq!(
+        inputs.len(),
+        xs.len(),
+        "{spec:?} needs {} operands",
+        inputs.len()
+    );
+
+    let mut sizes = HashMap::new();
+    for (labels, x) in inputs.iter().zip(xs) {
+        assert_eq!(
+            labels.len(),
+            x.shape()[..].len(),
+            "{labels:?} don't match {:?}",
+            x.shape()
+        );
+        for (c, d) in labels.iter().zip(&x.shape()[..]) {
+            let size = *sizes.entry(*c).or_insert(*d);
+            assert_eq!(size, *d, "Label {c:?} has sizes {size} and {d}");
+        }
+    }
+
+    // Labels of a single operand that are not in the output can be summed right away
+    let used = |c: &char, i: usize| {
+        output.contains(c)
+            || inputs
+                .iter()
+                .enumerate()
+                .any(|(j, l)| j != i && l.contains(c))
+    };
+    let (xs, inputs): (Vec<_>, Vec<_>) = xs
+        .iter()
+        .zip(&inputs)
+        .enumerate()
+        .map(|(i, (x, l))| sum_except(x.clone(), l, |c| used(c, i)))
+        .unzip();
+
+    if let [a, b] = &xs[..] {
+        if let Some(y) = contract(a, &inputs[0], b, &inputs[1], &output, &sizes) {
+            return y;
+        }
+    }
+
+    // Outer product over all labels, then sum over the ones not in the output
+    let mut order = output.clone();
+    for c in inputs.iter().flatten() {
+        if !order.contains(c) {
+            order.push(*c);
+        }
+    }
+    let prod = xs
+        .into_iter()
+        .zip(&inputs)
+        .map(|(x, l)| align(x, l, &order, &sizes))
+        .reduce(|a, b| a * b)
+        .unwrap();
+    sum_except(prod, &order, |c| output.contains(c)).0
+}
+
+/// Lowers `a`, `b` into a batched matrix product, if they contract over a single label and keep at
+/// most one label each.
+fn contract<T: Value, E: Eval>(
+    a: &Expr<T, E>,
+    la: &[char],
+    b: &Expr<T, E>,
+    lb: &[char],
+    output: &[char],
+    sizes: &HashMap<char, usize>,
+) -> Option<Expr<T, E>> {
+    let only = |x: &[char], y: &[char]| -> Vec<char> {
+        output
+            .iter()
+            .copied()
+            .filter(|c| x.contains(c) && !y.contains(c))
+            .collect()
+    };
+    let batch: Vec<char> = output
+        .iter()
+        .copied()
+        .filter(|c| la.contains(c) && lb.contains(c))
+        .collect();
+    let (m, n) = (only(la, lb), only(lb, la));
+    let k: Vec<char> = la
+        .iter()
+        .copied()
+        .filter(|c| lb.contains(c) && !output.contains(c))
+        .collect();
+    if k.len() != 1 || m.len() > 1 || n.len() > 1 {
+        return None;
+    }
+
+    // Missing free axes become axes of size 1, removed again after the product
+    let mut l = align(a.clone(), la, &[&batch[..], &m, &k].concat(), sizes);
+    if m.is_empty() {
+        l = l.broadcast(batch.len(), 1);
+    }
+    let mut r = align(b.clone(), lb, &[&batch[..], &k, &n].concat(), sizes);
+    if n.is_empty() {
+        r = r.broadcast(batch.len() + 1, 1);
+    }
+    let mut y = l.matmul(r);
+    if n.is_empty() {
+        y = y.sum_axis(batch.len() + 1);
+    }
+    if m.is_empty() {
+        y = y.sum_axis(batch.len());
+    }
+    Some(align(y, &[&batch[..], &m, &n].concat(), output, sizes))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::einsum::{einsum, Spec};
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::{arr1, arr2};
+
+    #[test]
+    fn test_parse() {
+        let s = Spec::parse("bij, bjk -> bik");
+        assert_eq!(s.inputs, vec![vec!['b', 'i', 'j'], vec!['b', 'j', 'k']]);
+        assert_eq!(s.output, vec!['b', 'i', 'k']);
+        assert_eq!(Spec::parse("ji,jk").output, vec!['i', 'k']);
+        assert_eq!(Spec::parse("i,i").output, vec![]);
+    }
+
+    #[test]
+    fn test_einsum() {
+        let mut e = TestEv::new();
+        let a: Expr<f32, TestEv> = param(shape![2, 3]);
+        let b: Expr<f32, TestEv> = param(shape![3]);
+        let outs = [
+            einsum("ij,j->i", &[a.clone(), b.clone()]),
+            einsum("ij->j", std::slice::from_ref(&a)),
+            einsum("ij,j,j->", &[a.clone(), b.clone(), b.clone()]),
+        ];
+        let outer = einsum("ij,k->kji", &[a.clone(), b.clone()]);
+        assert_eq!(outer.shape(), &shape![3, 3, 2]);
+        assert_eq!(
+            einsum("ij,ik", &[a.clone(), a.clone()]).shape(),
+            &shape![3, 3]
+        );
+
+        let (ab, bb) = (a.eval(&mut e), b.eval(&mut e));
+        let bufs = outs.map(|y| y.eval(&mut e));
+        let mut cpu = Cpu::new();
+        cpu.set(ab, arr2(&[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn());
+        cpu.set(bb, arr1(&[1.0, 0.0, -1.0]).into_dyn());
+        let bld = e.emitter();
+        assert_eq!(cpu.get(bld, bufs[0]), arr1(&[-2.0, -2.0]).into_dyn());
+        assert_eq!(cpu.get(bld, bufs[1]), arr1(&[5.0, 7.0, 9.0]).into_dyn());
+        assert_eq!(cpu.get(bld, bufs[2]).sum(), 14.0);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug)]
+/// Rows of a table at integer indices, with a sparse gradient for the table.
+struct Embedding<T: Value, E: Eval> {
+    shape: Shape,
+    table: Expr<T, E>,
+    idx: Expr<i64, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Embedding<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.table.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.table);
+        f(&self.idx);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let table = self.table.eval(e);
+        let idx = self.idx.eval(e);
+        e.emitter()
+            .emit(OpType::Gather { axis: 0 }, &self.shape, table, idx)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        Some(self.table.tangent()?.gather(0, self.idx.clone()))
+    }
+
+    // Batched tables lose the sparse gradient, they go through a regular gather
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        if b.get(&self.table).is_some() {
+            let dense = self.table.clone().gather(0, self.idx.clone());
+            return dense.0._impl.vmap(b);
+        }
+        Some(embedding(&self.table, b.get(&self.idx)?))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        self.table.accumulate_sparse(e, || {
+            let rows = self.idx.shape().prod();
+            let values = grad.reshape(self.table.shape().set(0, rows));
+            (self.idx.clone().flatten(), values)
+        });
+    }
+}
+
+/// Rows of `table` at `indices`, giving a tensor of the indices' shape followed by the row shape.
+/// The table gets a sparse gradient holding only the looked up rows, see [`Expr::sparse_grad`].
+pub fn embedding<T: Value, E: Eval>(
+    table: impl Into<Expr<T, E>>,
+    indices: Expr<i64, E>,
+) -> Expr<T, E> {
+    let table = table.into();
+    assert!(
+        !table.shape()[..].is_empty(),
+        "Embedding tables need at least one axis"
+    );
+    let shape = Shape::from([&indices.shape()[..], &table.shape()[1..]].concat());
+    Expr(ExprData::new(Embedding {
+        shape,
+        table,
+        idx: indices,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::constant::constant;
+    use crate::hl::expr::embedding::embedding;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::Expr;
+    use crate::hl::test::TestEv;
+    use crate::shape;
+
+    #[test]
+    fn test_sparse_grad() {
+        let mut e = TestEv::new();
+        let table: Expr<f32, TestEv> = param(shape![1000, 4]);
+        let idx = constant(shape![2, 2], vec![3.0, 7.0, 3.0, 0.0]);
+        let y = embedding(&table, idx.clone());
+        assert_eq!(y.shape(), &shape![2, 2, 4]);
+
+        y.backward(&mut e);
+        let sparse = table.sparse_grad().unwrap();
+        assert_eq!(sparse.indices.shape(), &shape![4]);
+        assert_eq!(sparse.values.shape(), &shape![4, 4]);
+        assert!(table.0.grad.borrow().is_none());
+        assert_eq!(table.grad().unwrap().shape(), &shape![1000, 4]);
+
+        // Tables computed from params pass a dense gradient on
+        table.zero_grad();
+        let scaled = table.clone() * &table;
+        embedding(&scaled, idx).backward(&mut e);
+        assert!(scaled.sparse_grad().is_none());
+        assert!(table.sparse_grad().is_none() && table.grad().is_some());
+    }
+}
@@ -0,0 +1,53 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug)]
+/// Reverses the order of elements along an axis.
+struct Flip<T: Value, E: Eval> {
+    axis: usize,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Flip<T, E> {
+    fn shape(&self) -> &Shape {
+        self.x.shape()
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let op = OpType::Flip { axis: self.axis };
+        e.emitter().emit(op, self.x.shape(), x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        Some(self.x.tangent()?.flip(self.axis as isize))
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        Some(b.get(&self.x)?.flip(self.axis as isize + 1))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        self.x.accumulate(e, || grad.flip(self.axis as isize));
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Reverses the order of elements along `axis`
+    pub fn flip(self, axis: isize) -> Expr<T, E> {
+        Expr(ExprData::new(Flip {
+            axis: self.shape().wrap(axis),
+            x: self,
+        }))
+    }
+}
@@ -0,0 +1,134 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::BufId;
+use std::cell::Cell;
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Restores the previous gradient mode when dropped, even when unwinding
+struct Mode(bool);
+
+impl Mode {
+    fn set(enabled: bool) -> Self {
+        Mode(ENABLED.with(|m| m.replace(enabled)))
+    }
+}
+
+impl Drop for Mode {
+    fn drop(&mut self) {
+        ENABLED.with(|m| m.set(self.0));
+    }
+}
+
+/// Whether expressions built right now track gradients
+pub fn is_grad_enabled() -> bool {
+    ENABLED.with(|m| m.get())
+}
+
+/// Runs `f` with gradient tracking disabled. Expressions built inside don't require grad, so the
+/// backwards pass skips them.
+pub fn no_grad<R>(f: impl FnOnce() -> R) -> R {
+    let _mode = Mode::set(false);
+    f()
+}
+
+/// Runs `f` with gradient tracking enabled, undoing an enclosing [`no_grad`]
+pub fn enable_grad<R>(f: impl FnOnce() -> R) -> R {
+    let _mode = Mode::set(true);
+    f()
+}
+
+#[derive(Debug)]
+/// Evaluates to its input, but is a leaf for the backwards pass.
+struct Detach<T: Value, E: Eval> {
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Detach<T, E> {
+    fn shape(&self) -> &Shape {
+        self.x.shape()
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {}
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        self.x.eval(e)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        None
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        Some(b.get(&self.x)?.detach())
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {}
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Same value, with the gradient stopped here
+    pub fn detach(self) -> Expr<T, E> {
+        Expr(ExprData::new(Detach { x: self }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::grad::{enable_grad, is_grad_enabled, no_grad};
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::Expr;
+    use crate::hl::test::TestEv;
+    use crate::shape;
+
+    #[test]
+    fn test_no_grad() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let w: Expr<f32, TestEv> = param(shape![3]);
+
+        let y = no_grad(|| {
+            assert!(enable_grad(is_grad_enabled));
+            x.clone() * &w
+        });
+        assert!(is_grad_enabled());
+        assert!(!y.requires_grad());
+
+        y.backward(&mut e);
+        assert!(x.grad().is_none());
+        assert!(w.grad().is_none());
+    }
+
+    #[test]
+    fn test_frozen() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let w: Expr<f32, TestEv> = param(shape![3]);
+        w.set_requires_grad(false);
+
+        let y = x.clone() * &w + &w;
+        y.backward(&mut e);
+        assert!(x.grad().is_some());
+        assert!(w.grad().is_none());
+    }
+
+    #[test]
+    fn test_detach() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let w: Expr<f32, TestEv> = param(shape![3]);
+
+        let y = x.clone().detach() * &w;
+        y.backward(&mut e);
+        assert!(x.grad().is_none());
+        assert!(w.grad().is_some());
+        y.eval(&mut e);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::{constant, full, zeros};
+use crate::hl::expr::select::select;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+/// Constant index vector
+pub(crate) fn indices<E: Eval>(idx: impl IntoIterator<Item = usize>) -> Expr<i64, E> {
+    let idx: Vec<f64> = idx.into_iter().map(|i| i as f64).collect();
+    constant(Shape::from(vec![idx.len()]), idx)
+}
+
+/// Shape of indexing `x` along `axis` with indices of shape `idx`, the axis is replaced by them
+fn indexed_shape(x: &Shape, axis: usize, idx: &Shape) -> Shape {
+    let x = &x[..];
+    Shape::from([&x[..axis], &idx[..], &x[axis + 1..]].concat())
+}
+
+/// Offsets turning indices into a batch of `n` long axes into indices into the merged axis
+fn batch_offsets<E: Eval>(idx: &Shape, n: usize) -> Expr<i64, E> {
+    let per = idx[1..].iter().product::<usize>();
+    let offs = (0..idx.prod()).map(|p| ((p / per) * n) as f64).collect();
+    constant(idx.clone(), offs)
+}
+
+/// Moves axis `from` of an `ndim` axes tensor to `to`, returning the permutation
+fn moved(ndim: usize, from: usize, to: usize) -> Vec<usize> {
+    let mut axes: Vec<usize> = (0..ndim).filter(|a| *a != from).collect();
+    axes.insert(to, from);
+    axes
+}
+
+#[derive(Debug)]
+/// Elements of `x` at `idx` along an axis.
+struct Gather<T: Value, E: Eval> {
+    axis: usize,
+    shape: Shape,
+    x: Expr<T, E>,
+    idx: Expr<i64, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Gather<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+        f(&self.idx);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let idx = self.idx.eval(e);
+        let op = OpType::Gather { axis: self.axis };
+        e.emitter().emit(op, &self.shape, x, idx)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        Some(
+            self.x
+                .tangent()?
+                .gather(self.axis as isize, self.idx.clone()),
+        )
+    }
+
+    // Batched indices pick from their own example, so the batch and indexed axes are merged
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        let (a, k) = (self.axis, self.idx.shape()[..].len());
+        let Some(idx) = b.get(&self.idx) else {
+            return Some(b.get(&self.x)?.gather(a as isize + 1, self.idx.clone()));
+        };
+        let x = b.get_or_broadcast(&self.x);
+        let n = x.shape()[a as isize + 1];
+        let merged = x.shape().remove(a as isize + 1).set(0, b.size() * n);
+        let x = x
+            .permute(&moved(merged[..].len() + 1, a + 1, 1))
+            .reshape(merged);
+        let idx = batch_offsets(idx.shape(), n) + idx;
+
+        // [B, I.., pre.., post..] -> [B, pre.., I.., post..]
+        let y = x.gather(0, idx);
+        let mut axes: Vec<usize> = (0..y.shape()[..].len()).collect();
+        axes[1..1 + a + k].rotate_left(k);
+        Some(y.permute(&axes))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        self.x.accumulate(e, || {
+            zeros(self.x.shape().clone()).index_add(self.axis as isize, self.idx.clone(), grad)
+        });
+    }
+}
+
+#[derive(Debug)]
+/// `base` with `values` added at `idx` along an axis, repeated indices add up.
+struct IndexAdd<T: Value, E: Eval> {
+    axis: usize,
+    base: Expr<T, E>,
+    idx: Expr<i64, E>,
+    values: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for IndexAdd<T, E> {
+    fn shape(&self) -> &Shape {
+        self.base.shape()
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.base.accept(v);
+        self.values.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.base);
+        f(&self.idx);
+        f(&self.values);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let base = self.base.eval(e);
+        let idx = self.idx.eval(e);
+        let values = self.values.eval(e);
+        let op = OpType::IndexAdd { axis: self.axis };
+        e.emitter()
+            .emit_n(op, self.base.shape(), &[base, idx, values])
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let (tb, tv) = (self.base.tangent(), self.values.tangent());
+        if tb.is_none() && tv.is_none() {
+            return None;
+        }
+        let tb = tb.unwrap_or_else(|| zeros(self.base.shape().clone()));
+        let tv = tv.unwrap_or_else(|| zeros(self.values.shape().clone()));
+        Some(tb.index_add(self.axis as isize, self.idx.clone(), tv))
+    }
+
+    // Like `Gather::vmap`, batched indices merge the batch and indexed axes
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        let (a, k) = (self.axis, self.idx.shape()[..].len());
+        let (bb, bi, bv) = (b.get(&self.base), b.get(&self.idx), b.get(&self.values));
+        if bb.is_none() && bi.is_none() && bv.is_none() {
+            return None;
+        }
+        let base = bb.unwrap_or_else(|| self.base.clone().broadcast(0, b.size()));
+        let values = bv.unwrap_or_else(|| self.values.clone().broadcast(0, b.size()));
+        let Some(idx) = bi else {
+            return Some(base.index_add(a as isize + 1, self.idx.clone(), values));
+        };
+
+        // [B, pre.., n, post..] -> [B * n, pre.., post..]
+        let shape = base.shape().clone();
+        let n = shape[a as isize + 1];
+        let ndim = shape[..].len();
+        let merged = shape.remove(a as isize + 1).set(0, b.size() * n);
+        let base = base.permute(&moved(ndim, a + 1, 1)).reshape(merged);
+        // [B, pre.., I.., post..] -> [B, I.., pre.., post..]
+        let mut axes: Vec<usize> = (0..values.shape()[..].len()).collect();
+        axes[1..1 + a + k].rotate_right(k);
+        let values = values.permute(&axes);
+
+        let idx = batch_offsets(idx.shape(), n) + idx;
+        let y = base.index_add(0, idx, values);
+        let unmerged = shape.remove(a as isize + 1).insert(1, n);
+        Some(y.reshape(unmerged).permute(&moved(ndim, 1, a + 1)))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        self.base.accumulate(e, || grad.clone());
+        self.values
+            .accumulate(e, || grad.gather(self.axis as isize, self.idx.clone()));
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Elements at `idx` along `axis`, like `x[:, idx]` in NumPy. The axis is replaced by the axes
+    /// of `idx`, which hold indices in `0..n`.
+    pub fn gather(self, axis: isize, idx: Expr<i64, E>) -> Expr<T, E> {
+        let axis = self.shape().wrap(axis);
+        Expr(ExprData::new(Gather {
+            axis,
+            shape: indexed_shape(self.shape(), axis, idx.shape()),
+            x: self,
+            idx,
+        }))
+    }
+
+    /// Adds `values` at `idx` along `axis`, the reverse of [`Expr::gather`]. Values at repeated
+    /// indices add up.
+    pub fn index_add(
+        self,
+        axis: isize,
+        idx: Expr<i64, E>,
+        values: impl Into<Expr<T, E>>,
+    ) -> Expr<T, E> {
+        let (axis, values) = (self.shape().wrap(axis), values.into());
+        assert_eq!(
+            values.shape(),
+            &indexed_shape(self.shape(), axis, idx.shape()),
+            "Values don't match indices {:?} into axis {axis} of {:?}",
+            idx.shape(),
+            self.shape()
+        );
+        Expr(ExprData::new(IndexAdd {
+            axis,
+            base: self,
+            idx,
+            values,
+        }))
+    }
+
+    /// Replaces elements at `idx` along `axis` with `values`. Indices have to be unique.
+    pub fn scatter(
+        self,
+        axis: isize,
+        idx: Expr<i64, E>,
+        values: impl Into<Expr<T, E>>,
+    ) -> Expr<T, E> {
+        let values = values.into();
+        let ones = full::<T, E>(values.shape().clone(), 1.0);
+        let hit = zeros::<T, E>(self.shape().clone()).index_add(axis, idx.clone(), ones);
+        let placed = zeros(self.shape().clone()).index_add(axis, idx, values);
+        select(hit.gt(zeros(self.shape().clone())), placed, self)
+    }
+
+    /// Replaces elements where `mask` is true with `value`. The mask broadcasts to this shape.
+    pub fn masked_fill(self, mask: Expr<bool, E>, value: f64) -> Expr<T, E> {
+        let mask = mask.broadcast_to(self.shape());
+        select(mask, full(self.shape().clone(), value), self)
+    }
+
+    /// Elements along `axis` where `mask` is true. Shapes are fixed when building the graph, so the
+    /// mask is too.
+    pub fn compress(self, axis: isize, mask: &[bool]) -> Expr<T, E> {
+        assert_eq!(
+            mask.len(),
+            self.shape()[axis],
+            "Mask doesn't match axis {axis} of {:?}",
+            self.shape()
+        );
+        let idx = mask.iter().enumerate().filter(|(_, m)| **m).map(|(i, _)| i);
+        self.gather(axis, indices(idx))
+    }
+}
@@ -0,0 +1,91 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{sum_opt, Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug)]
+/// Product of the matrices in the last two axes, `[B.., m, k] @ [B.., k, n] -> [B.., m, n]`. Both
+/// operands have the same batch axes, broadcasting happens before.
+struct MatMul<T: Value, E: Eval> {
+    shape: Shape,
+    l: Expr<T, E>,
+    r: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for MatMul<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.l.accept(v);
+        self.r.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.l);
+        f(&self.r);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let l = self.l.eval(e);
+        let r = self.r.eval(e);
+        e.emitter().emit(OpType::MatMul, &self.shape, l, r)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        sum_opt::<E>(
+            self.l.tangent().map(|t| t.matmul(self.r.astype())),
+            self.r.tangent().map(|t| self.l.astype().matmul(t)),
+        )
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        if b.get(&self.l).is_none() && b.get(&self.r).is_none() {
+            return None;
+        }
+        Some(
+            b.get_or_broadcast(&self.l)
+                .matmul(b.get_or_broadcast(&self.r)),
+        )
+    }
+
+    // Conjugated for complex values, see `Un::backward`
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        let adjoint = |x: &Expr<T, E>| x.astype::<E::Grad>().conj().transpose(-2, -1);
+        self.l
+            .accumulate(e, || grad.clone().matmul(adjoint(&self.r)));
+        self.r.accumulate(e, || adjoint(&self.l).matmul(grad));
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Matrix product over the last two axes, `[.., m, k] @ [.., k, n] -> [.., m, n]`. Leading
+    /// axes are batch axes and broadcast against each other like in NumPy.
+    pub fn matmul(self, rhs: impl Into<Expr<T, E>>) -> Expr<T, E> {
+        let rhs = rhs.into();
+        let (ls, rs) = (self.shape().clone(), rhs.shape().clone());
+        assert!(
+            ls[..].len() >= 2 && rs[..].len() >= 2,
+            "Matmul needs at least 2 axes, got {ls:?} @ {rs:?}"
+        );
+        assert_eq!(ls[-1], rs[-2], "Inner axes differ in {ls:?} @ {rs:?}");
+
+        let batch = Shape::from(&ls[..-2])
+            .broadcast_with(&Shape::from(&rs[..-2]))
+            .unwrap_or_else(|| panic!("Batch axes don't broadcast in {ls:?} @ {rs:?}"));
+        let with = |m: usize, n: usize| Shape::from([&batch[..], &[m, n]].concat());
+        let l = self.broadcast_to(&with(ls[-2], ls[-1]));
+        let r = rhs.broadcast_to(&with(rs[-2], rs[-1]));
+        Expr(ExprData::new(MatMul {
+            shape: with(ls[-2], rs[-1]),
+            l,
+            r,
+        }))
+    }
+}
+
+/// Matrix product `a @ b`, see [`Expr::matmul`]
+pub fn matmul<T: Value, E: Eval>(a: impl Into<Expr<T, E>>, b: impl Into<Expr<T, E>>) -> Expr<T, E> {
+    a.into().matmul(b)
+}
@@ -1,12 +1,45 @@
+pub mod act;
+pub mod batch;
 pub mod bin;
+pub mod broadcast;
+pub mod cast;
+pub mod cat;
+pub mod check;
+pub mod cmp;
+pub mod constant;
+pub mod conv;
+pub mod custom;
+pub mod diff;
+pub mod einsum;
+pub mod embedding;
+pub mod flip;
+pub mod grad;
+pub mod index;
+pub mod matmul;
+pub mod pad;
 pub mod param;
+pub mod part;
+pub mod permute;
+pub mod reduce;
+pub mod reshape;
+pub mod select;
+pub mod slice;
+pub mod stats;
 pub mod un;
 
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::cat::cat;
+use crate::hl::expr::constant::{ones, zeros};
+use crate::hl::expr::grad::is_grad_enabled;
 use crate::hl::expr::param::Param;
 use crate::hl::shape::Shape;
-use crate::ml::{BufId, MLBuilder};
+use crate::hl::expr::cast::Cast;
+use crate::ml::{BufId, DType, MLBuilder};
+use half::{bf16, f16};
+use num::Complex;
 use std::any::{type_name, Any as StdAny, Any, TypeId};
 use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::rc::Rc;
@@ -28,16 +61,146 @@ impl<T> Clone for Ten<T> {
     }
 }
 
-pub trait Value: Debug + 'static {}
-impl Value for f32 {}
+/// Complex number with `f32` parts
+pub type C64 = Complex<f32>;
+/// Complex number with `f64` parts
+pub type C128 = Complex<f64>;
+
+pub trait Value: Debug + Copy + 'static {
+    /// Element type of buffers holding this value
+    const DTYPE: DType;
+    /// Type of the real and imaginary parts, `Self` for real values
+    type Real: Value;
+
+    fn to_f64(self) -> f64;
+    fn from_f64(v: f64) -> Self;
+
+    fn to_c128(self) -> C128 {
+        C128::new(self.to_f64(), 0.0)
+    }
+    /// Drops the imaginary part when `Self` is real
+    fn from_c128(v: C128) -> Self {
+        Self::from_f64(v.re)
+    }
+}
+
+macro_rules! value {
+    ($($t:ty => $d:ident),* $(,)?) => {$(
+        impl Value for $t {
+            const DTYPE: DType = DType::$d;
+            type Real = $t;
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+            fn from_f64(v: f64) -> Self {
+                v as $t
+            }
+        }
+    )*};
+}
+
+value!(u8 => U8, i32 => I32, i64 => I64, f32 => F32, f64 => F64);
+
+impl Value for bool {
+    const DTYPE: DType = DType::Bool;
+    type Real = bool;
+
+    fn to_f64(self) -> f64 {
+        self as u8 as f64
+    }
+    fn from_f64(v: f64) -> Self {
+        v != 0.0
+    }
+}
+
+impl Value for f16 {
+    const DTYPE: DType = DType::F16;
+    type Real = f16;
+
+    fn to_f64(self) -> f64 {
+        f16::to_f64(self)
+    }
+    fn from_f64(v: f64) -> Self {
+        f16::from_f64(v)
+    }
+}
+
+impl Value for bf16 {
+    const DTYPE: DType = DType::BF16;
+    type Real = bf16;
+
+    fn to_f64(self) -> f64 {
+        bf16::to_f64(self)
+    }
+    fn from_f64(v: f64) -> Self {
+        bf16::from_f64(v)
+    }
+}
+
+macro_rules! complex {
+    ($($r:ty => $d:ident),*) => {$(
+        impl Value for Complex<$r> {
+            const DTYPE: DType = DType::$d;
+            type Real = $r;
+
+            /// Real part
+            fn to_f64(self) -> f64 {
+                self.re as f64
+            }
+            fn from_f64(v: f64) -> Self {
+                Complex::new(v as $r, 0.0)
+            }
+            fn to_c128(self) -> C128 {
+                C128::new(self.re as f64, self.im as f64)
+            }
+            fn from_c128(v: C128) -> Self {
+                Complex::new(v.re as $r, v.im as $r)
+            }
+        }
+    )*};
+}
+
+complex!(f32 => C64, f64 => C128);
+
+/// Element type of binary ops mixing `Self` and `R`, the static counterpart of [`DType::promote`]
+pub trait Promote<R: Value>: Value {
+    type Output: Value;
+}
+
+macro_rules! promote {
+    ($($l:ty: $($r:ty => $o:ty),*;)*) => {$($(
+        impl Promote<$r> for $l {
+            type Output = $o;
+        }
+    )*)*};
+}
+
+promote! {
+    bool: bool => bool, u8 => u8, i32 => i32, i64 => i64, f16 => f16, bf16 => bf16, f32 => f32, f64 => f64, C64 => C64, C128 => C128;
+    u8: bool => u8, u8 => u8, i32 => i32, i64 => i64, f16 => f16, bf16 => bf16, f32 => f32, f64 => f64, C64 => C64, C128 => C128;
+    i32: bool => i32, u8 => i32, i32 => i32, i64 => i64, f16 => f16, bf16 => bf16, f32 => f32, f64 => f64, C64 => C64, C128 => C128;
+    i64: bool => i64, u8 => i64, i32 => i64, i64 => i64, f16 => f16, bf16 => bf16, f32 => f32, f64 => f64, C64 => C64, C128 => C128;
+    f16: bool => f16, u8 => f16, i32 => f16, i64 => f16, f16 => f16, bf16 => f32, f32 => f32, f64 => f64, C64 => C64, C128 => C128;
+    bf16: bool => bf16, u8 => bf16, i32 => bf16, i64 => bf16, f16 => f32, bf16 => bf16, f32 => f32, f64 => f64, C64 => C64, C128 => C128;
+    f32: bool => f32, u8 => f32, i32 => f32, i64 => f32, f16 => f32, bf16 => f32, f32 => f32, f64 => f64, C64 => C64, C128 => C128;
+    f64: bool => f64, u8 => f64, i32 => f64, i64 => f64, f16 => f64, bf16 => f64, f32 => f64, f64 => f64, C64 => C128, C128 => C128;
+    C64: bool => C64, u8 => C64, i32 => C64, i64 => C64, f16 => C64, bf16 => C64, f32 => C64, f64 => C128, C64 => C64, C128 => C128;
+    C128: bool => C128, u8 => C128, i32 => C128, i64 => C128, f16 => C128, bf16 => C128, f32 => C128, f64 => C128, C64 => C128, C128 => C128;
+}
 
 pub trait Eval: Debug + 'static {
+    /// Element type of gradients and tangents. Has to be complex for graphs with complex values.
     type Grad: Value;
 
     /// Make a new unique id for expression node
     fn mkid(&mut self) -> u64;
-    /// Whether we're interested in tracking gradients.
-    fn grad(&self) -> bool;
+    /// Whether we're interested in tracking gradients. Backwards passes do nothing when this is false.
+    ///
+    /// Follows [`no_grad`](grad::no_grad) and [`enable_grad`](grad::enable_grad) scopes by default.
+    fn grad(&self) -> bool {
+        is_grad_enabled()
+    }
 
     fn enter(&self, id: u64);
     fn exit(&self, id: u64);
@@ -52,20 +215,115 @@ impl<T: Value, E: Eval> Into<Expr<T, E>> for &Expr<T, E> {
     }
 }
 
+/// Gradient that is zero outside of some rows along the first axis. Row `indices[i]` holds
+/// `values[i]`, repeated indices add up.
+#[derive(Debug)]
+pub struct SparseGrad<E: Eval> {
+    pub indices: Expr<i64, E>,
+    pub values: Expr<E::Grad, E>,
+}
+
+impl<E: Eval> Clone for SparseGrad<E> {
+    fn clone(&self) -> Self {
+        Self {
+            indices: self.indices.clone(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+impl<E: Eval> SparseGrad<E> {
+    /// The same gradient as a dense tensor of `shape`
+    pub fn to_dense(&self, shape: Shape) -> Expr<E::Grad, E> {
+        zeros(shape).index_add(0, self.indices.clone(), self.values.clone())
+    }
+}
+
 /// General visitior for working traversing the expression tree
 pub trait Visitor<T: Value, E: Eval> {
     fn visit_param(&mut self, p: &Param<T, E>);
 }
 
+/// Type-erased handle to an expression node. Lets graph walks cross nodes with different value types.
+pub trait Node<E: Eval> {
+    /// Identity of the underlying node, stable while any handle to it is alive
+    fn key(&self) -> usize;
+    fn boxed(&self) -> Box<dyn Node<E>>;
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>));
+    fn requires_grad(&self) -> bool;
+    /// Pushes the gradient this node got in the current backwards pass into its inputs
+    fn backward(&self, e: &mut E);
+    /// Pulls tangents of the inputs into this node, unless it already has one
+    fn forward(&self, e: &mut E);
+    fn zero_grad(&self);
+    /// Replaces the stored gradients, returning the old ones
+    fn swap_grads(&self, g: Grads<E>) -> Grads<E>;
+    /// Adds `g` to the stored gradients
+    fn add_grads(&self, g: Grads<E>);
+    fn zero_tangent(&self);
+}
+
+/// Dense and sparse gradient stored in a node, see [`Node::swap_grads`]
+pub type Grads<E> = (Option<Expr<<E as Eval>::Grad, E>>, Option<SparseGrad<E>>);
+
+/// Sum of two optional gradients, where `None` stands for zero
+pub(crate) fn sum_opt<E: Eval>(
+    a: Option<Expr<E::Grad, E>>,
+    b: Option<Expr<E::Grad, E>>,
+) -> Option<Expr<E::Grad, E>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Concatenation of two optional sparse gradients, where `None` stands for zero
+fn cat_sparse<E: Eval>(
+    a: Option<SparseGrad<E>>,
+    b: Option<SparseGrad<E>>,
+) -> Option<SparseGrad<E>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(SparseGrad {
+            indices: cat(&[a.indices, b.indices], 0),
+            values: cat(&[a.values, b.values], 0),
+        }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// All nodes reachable from `root`, ordered so that every node comes after its inputs
+pub(crate) fn topo<E: Eval>(root: &dyn Node<E>) -> Vec<Box<dyn Node<E>>> {
+    fn visit<E: Eval>(n: &dyn Node<E>, seen: &mut HashSet<usize>, out: &mut Vec<Box<dyn Node<E>>>) {
+        if !seen.insert(n.key()) {
+            return;
+        }
+        n.inputs(&mut |i| visit(i, seen, out));
+        out.push(n.boxed());
+    }
+
+    let mut out = vec![];
+    visit(root, &mut HashSet::new(), &mut out);
+    out
+}
+
 /// Expression implementation. In the forward pass, it should evaluate subexpressions
 ///
 /// In backwards passes it should prepare the backwards graph from the forward one
 pub trait ExprImpl<T: Value, E: Eval>: Any + Debug {
     fn shape(&self) -> &Shape;
     fn accept(&self, v: &mut dyn Visitor<T, E>);
+    /// Calls `f` with every direct input of this expression
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>));
     /// Evaluates this expression, producing materializable resutl
     fn eval(&self, id: u64, e: &mut E) -> BufId;
-    /// Implements backwards pass for a graph
+    /// Implements forward mode differentiation. Builds the tangent of this expression from the
+    /// tangents of its inputs, `None` meaning the tangent is zero
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>>;
+    /// Rewrites this expression to work on a batch, see [`Batch`]. Returns `None` if the expression
+    /// doesn't depend on the batched input
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>>;
+    /// Implements backwards pass for a graph. Should only accumulate into the inputs, the traversal
+    /// itself is driven by [`Expr::backward`]
     fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>);
 
     /// Augmented Any like functionality
@@ -78,12 +336,184 @@ impl<T: Value, E: Eval> Expr<T, E> {
     pub fn eval(&self, e: &mut E) -> BufId {
         self.0.eval(e)
     }
-    /// If this tensor requires grad
-    pub fn backward<V: Value, F: FnOnce() -> Expr<V, E>>(&self, e: &mut E, v: F) {
-        // DO NOT RECURSIVELY CALL INTERNAL BACKWARD
+    /// Runs the backwards pass from this expression, seeded with ones.
+    ///
+    /// Visits every node reachable from here exactly once, in reverse topological order, so each node
+    /// sees its complete gradient before pushing it into its inputs.
+    ///
+    /// Gradients are built out of regular expressions, so they can be differentiated again. To get
+    /// second derivatives, take the gradient, clear the gradients of both this expression and the
+    /// gradient with [`Expr::zero_grad`], and call `backward` on the gradient.
+    pub fn backward(&self, e: &mut E) {
+        self.backward_with(e, ones(self.shape().clone()))
+    }
+    /// Runs the backwards pass seeded with `seed`, which has the shape of this expression.
+    ///
+    /// Nodes only push the gradient of this pass, which is added to their stored gradient at the
+    /// end. Repeated passes, or passes sharing part of the graph, therefore add up like separate
+    /// losses would.
+    pub fn backward_with(&self, e: &mut E, seed: Expr<E::Grad, E>) {
+        if !e.grad() {
+            return;
+        }
+        let nodes = topo::<E>(self);
+        let saved: Vec<Grads<E>> = nodes
+            .iter()
+            .map(|n| n.swap_grads(Default::default()))
+            .collect();
+        self.accumulate(e, || seed);
+
+        for n in nodes.iter().rev() {
+            n.backward(e);
+        }
+        for (n, s) in nodes.iter().zip(saved) {
+            n.add_grads(s);
+        }
+    }
+    /// Adds a gradient contribution to this tensor. Only called from [`ExprImpl::backward`].
+    ///
+    /// DO NOT RECURSIVELY CALL INTERNAL BACKWARD
+    pub fn accumulate<F: FnOnce() -> Expr<E::Grad, E>>(&self, e: &mut E, v: F) {
+        // Skipped before building the gradient, so subgraphs that need no gradient cost nothing
+        if !self.requires_grad() {
+            return;
+        }
+        let v = v();
+        let mut grad = self.0.grad.borrow_mut();
+        *grad = Some(match grad.take() {
+            Some(g) => g + v,
+            None => v,
+        });
+    }
+    /// Whether backwards passes compute a gradient for this tensor.
+    ///
+    /// Float params require grad, other expressions if any of their inputs does and they were not built
+    /// inside [`no_grad`](grad::no_grad).
+    pub fn requires_grad(&self) -> bool {
+        self.0.requires_grad.get()
+    }
+    /// Freezes or unfreezes this tensor. Only affects expressions built from it afterwards.
+    pub fn set_requires_grad(&self, requires_grad: bool) {
+        self.0.requires_grad.set(requires_grad);
+    }
+    /// Adds a gradient contribution that is zero outside of the rows at `indices` along the first
+    /// axis, which hold `values`. Params keep it sparse, other tensors add it to their dense
+    /// gradient. Only called from [`ExprImpl::backward`].
+    pub fn accumulate_sparse<F>(&self, e: &mut E, v: F)
+    where
+        F: FnOnce() -> (Expr<i64, E>, Expr<E::Grad, E>),
+    {
+        if !self.requires_grad() {
+            return;
+        }
+        let (indices, values) = v();
+        if !Expr::<T, E, Param<T, E>>::is(self.clone()) {
+            let shape = self.shape().clone();
+            return self.accumulate(e, || zeros(shape).index_add(0, indices, values));
+        }
+        let mut sparse = self.0.sparse_grad.borrow_mut();
+        *sparse = cat_sparse(sparse.take(), Some(SparseGrad { indices, values }));
+    }
+    /// Gradient accumulated by the backwards passes so far, including the sparse part
+    pub fn grad(&self) -> Option<Expr<E::Grad, E>> {
+        let sparse = self.sparse_grad().map(|g| g.to_dense(self.shape().clone()));
+        sum_opt::<E>(self.0.grad.borrow().clone(), sparse)
+    }
+    /// Row-indexed part of the gradient, which [`Expr::grad`] includes as a dense tensor
+    pub fn sparse_grad(&self) -> Option<SparseGrad<E>> {
+        self.0.sparse_grad.borrow().clone()
+    }
+    /// Clears gradients of this expression and everything it depends on
+    pub fn zero_grad(&self) {
+        for n in topo::<E>(self) {
+            n.zero_grad();
+        }
+    }
+    /// Seeds the forward mode pass, `t` is the direction in which this tensor changes
+    pub fn set_tangent(&self, t: Expr<E::Grad, E>) {
+        self.0.tangent.replace(Some(t));
+    }
+    pub fn tangent(&self) -> Option<Expr<E::Grad, E>> {
+        self.0.tangent.borrow().clone()
+    }
+    /// Runs the forward mode pass up to this expression. Produces the Jacobian-vector product of
+    /// this expression with tangents seeded by [`Expr::set_tangent`].
+    ///
+    /// Computed tangents are cached in the nodes, use [`Expr::zero_tangent`] before reseeding.
+    pub fn jvp(&self, e: &mut E) -> Expr<E::Grad, E> {
+        for n in topo::<E>(self) {
+            n.forward(e);
+        }
+        self.tangent().unwrap_or_else(|| zeros(self.shape().clone()))
+    }
+    /// Clears tangents of this expression and everything it depends on, including the seeds
+    pub fn zero_tangent(&self) {
+        for n in topo::<E>(self) {
+            n.zero_tangent();
+        }
     }
+    /// Converts elements to `V`, returns this expression if it already holds `V`
     pub fn astype<V: Value>(&self) -> Expr<V, E> {
-        todo!()
+        if let Ok(same) = (Box::new(self.clone()) as Box<dyn Any>).downcast::<Expr<V, E>>() {
+            return *same;
+        }
+        let out: Expr<V, E> = Expr(ExprData::new(Cast::new(self.clone())));
+        if !V::DTYPE.is_float() {
+            out.set_requires_grad(false);
+        }
+        out
+    }
+}
+
+impl<T: Value, E: Eval> Node<E> for Expr<T, E> {
+    fn key(&self) -> usize {
+        Rc::as_ptr(&self.0) as *const () as usize
+    }
+
+    fn boxed(&self) -> Box<dyn Node<E>> {
+        Box::new(self.clone())
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        self.0._impl.inputs(f)
+    }
+
+    fn requires_grad(&self) -> bool {
+        Expr::requires_grad(self)
+    }
+
+    fn backward(&self, e: &mut E) {
+        if let Some(grad) = self.grad() {
+            self.0._impl.backward(e, grad);
+        }
+    }
+
+    fn forward(&self, e: &mut E) {
+        if self.tangent().is_none() {
+            let t = self.0._impl.jvp(e);
+            self.0.tangent.replace(t);
+        }
+    }
+
+    fn zero_grad(&self) {
+        self.0.grad.replace(None);
+        self.0.sparse_grad.replace(None);
+    }
+
+    fn swap_grads(&self, (grad, sparse): Grads<E>) -> Grads<E> {
+        let old = self.0.grad.replace(grad);
+        (old, self.0.sparse_grad.replace(sparse))
+    }
+
+    fn add_grads(&self, (grad, sparse): Grads<E>) {
+        let old = self.0.grad.take();
+        self.0.grad.replace(sum_opt::<E>(grad, old));
+        let old = self.0.sparse_grad.take();
+        self.0.sparse_grad.replace(cat_sparse(sparse, old));
+    }
+
+    fn zero_tangent(&self) {
+        self.0.tangent.replace(None);
     }
 }
 
@@ -93,17 +523,28 @@ pub struct ExprData<T: Value, E: Eval, I: ?Sized = dyn ExprImpl<T, E> + 'static>
     pub _p: PhantomData<T>,
     pub id: Cell<u64>,
     pub val: Cell<Option<BufId>>,
-    pub grad: RefCell<Option<Ten<E::Grad>>>,
+    pub requires_grad: Cell<bool>,
+    pub grad: RefCell<Option<Expr<E::Grad, E>>>,
+    /// Row-indexed part of the gradient, see [`Expr::accumulate_sparse`]
+    pub sparse_grad: RefCell<Option<SparseGrad<E>>>,
+    pub tangent: RefCell<Option<Expr<E::Grad, E>>>,
     pub _impl: I,
 }
 
 impl<T: Value, E: Eval, I: ExprImpl<T, E> + Sized> ExprData<T, E, I> {
     pub fn new(i: I) -> Rc<ExprData<T, E, I>> {
+        let mut requires_grad = false;
+        if is_grad_enabled() {
+            i.inputs(&mut |n| requires_grad |= n.requires_grad());
+        }
         Rc::new(ExprData {
             _p: Default::default(),
             id: Cell::new(0),
             val: Cell::new(None),
+            requires_grad: Cell::new(requires_grad),
             grad: RefCell::new(None),
+            sparse_grad: RefCell::new(None),
+            tangent: RefCell::new(None),
             _impl: i,
         })
     }
@@ -139,6 +580,7 @@ impl<T: Value, E: Eval, I: ExprImpl<T, E> + ?Sized> ExprData<T, E, I> {
         e.enter(id);
         let out = self._impl.eval(id, e);
         e.exit(id);
+        self.val.set(Some(out));
         out
     }
 }
@@ -174,7 +616,7 @@ impl<T: Value, E: Eval, I: Sized + ExprImpl<T, E>> Expr<T, E, I> {
 }
 
 impl<T: Value, E: Eval> Expr<T, E> {
-    fn shape(&self) -> &Shape {
+    pub fn shape(&self) -> &Shape {
         self.0._impl.shape()
     }
 }
@@ -0,0 +1,119 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType, PadKind};
+
+#[derive(Debug)]
+/// Adds elements at both ends of an axis.
+struct Pad<T: Value, E: Eval> {
+    axis: usize,
+    before: usize,
+    after: usize,
+    kind: PadKind,
+    shape: Shape,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Pad<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let op = OpType::Pad {
+            axis: self.axis,
+            before: self.before,
+            after: self.after,
+            kind: self.kind,
+        };
+        e.emitter().emit(op, &self.shape, x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let kind = match self.kind {
+            PadKind::One => PadKind::Zero,
+            k => k,
+        };
+        Some(
+            self.x
+                .tangent()?
+                .pad(self.axis as isize, self.before, self.after, kind),
+        )
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        let x = b.get(&self.x)?;
+        Some(x.pad(self.axis as isize + 1, self.before, self.after, self.kind))
+    }
+
+    // Crops the gradient, padding copied from the input sends its part back
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        let (axis, before, after) = (self.axis as isize, self.before, self.after);
+        let n = self.x.shape()[axis];
+        let part = |start, len| grad.clone().narrow(axis, start, len);
+        let at = |g: Expr<E::Grad, E>, start: usize| {
+            let len = g.shape()[axis];
+            g.pad(axis, start, n - start - len, PadKind::Zero)
+        };
+        self.x.accumulate(e, || {
+            let mut g = part(before, n);
+            match self.kind {
+                PadKind::Zero | PadKind::One => {}
+                PadKind::Edge => {
+                    if before > 0 {
+                        g = g + at(part(0, before).sum(&[axis], true), 0);
+                    }
+                    if after > 0 {
+                        g = g + at(part(before + n, after).sum(&[axis], true), n - 1);
+                    }
+                }
+                PadKind::Mirror => {
+                    if before > 0 {
+                        g = g + at(part(0, before).flip(axis), 1);
+                    }
+                    if after > 0 {
+                        g = g + at(part(before + n, after).flip(axis), n - 1 - after);
+                    }
+                }
+            }
+            g
+        });
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Adds `before` and `after` elements at the ends of `axis`. `Mirror` reflects around the edge
+    /// elements without repeating them, so it can add at most one less than the axis holds.
+    pub fn pad(self, axis: isize, before: usize, after: usize, kind: PadKind) -> Expr<T, E> {
+        let axis = self.shape().wrap(axis);
+        let n = self.shape()[axis as isize];
+        if before == 0 && after == 0 {
+            return self;
+        }
+        match kind {
+            PadKind::Edge => assert!(n > 0, "Can't pad an empty axis with its edge"),
+            PadKind::Mirror => assert!(
+                before < n && after < n,
+                "Can't mirror {before} and {after} elements of an axis of {n}"
+            ),
+            PadKind::Zero | PadKind::One => {}
+        }
+        Expr(ExprData::new(Pad {
+            axis,
+            before,
+            after,
+            kind,
+            shape: self.shape().set(axis as isize, before + n + after),
+            x: self,
+        }))
+    }
+}
@@ -1,7 +1,8 @@
-use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Ten, Value, Visitor};
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::zeros;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Ten, Value, Visitor};
 use crate::hl::shape::Shape;
 use crate::ml::BufId;
-use crate::shape;
 use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
 
@@ -25,25 +26,34 @@ impl<T: Value, E: Eval> ExprImpl<T, E> for Param<T, E> {
         v.visit_param(self);
     }
 
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {}
+
     fn eval(&self, id: u64, e: &mut E) -> BufId {
-        e.emitter().buffer(shape![1])
+        e.emitter().buffer(self.shape.clone(), T::DTYPE)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        None
     }
 
-    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
-        todo!()
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        None
     }
+
+    // Params are leaves, their gradient stays in `ExprData::grad`
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {}
 }
 
 pub fn param<T: Value, E: Eval>(shape: Shape) -> Expr<T, E> {
-    Expr(ExprData::new(Param {
+    let p: Expr<T, E> = Expr(ExprData::new(Param {
         shape,
         _p: Default::default(),
-    }))
+    }));
+    p.set_requires_grad(T::DTYPE.is_float());
+    p
 }
 
+#[deprecated(note = "use `constant::zeros`")]
 pub fn zero<T: Value, E: Eval>(shape: Shape) -> Expr<T, E> {
-    Expr(ExprData::new(Param {
-        shape,
-        _p: Default::default(),
-    }))
+    zeros(shape)
 }
@@ -0,0 +1,138 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::{full, ones, zeros};
+use crate::hl::expr::select::select;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor, C128};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug, Clone, Copy)]
+/// Real valued parts of a possibly complex value
+enum PartOp {
+    /// Magnitude y = |x|
+    Abs,
+    /// Phase y = atan2(im(x), re(x))
+    Angle,
+    Real,
+    Imag,
+}
+
+#[derive(Debug)]
+struct Part<T: Value, E: Eval> {
+    op: PartOp,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T::Real, E> for Part<T, E> {
+    fn shape(&self) -> &Shape {
+        self.x.shape()
+    }
+
+    // Visitors are typed by the element type, so they can't continue into the input
+    fn accept(&self, v: &mut dyn Visitor<T::Real, E>) {}
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let op = match self.op {
+            PartOp::Abs => OpType::Abs,
+            PartOp::Angle => OpType::Angle,
+            PartOp::Real => OpType::Real,
+            PartOp::Imag => OpType::Imag,
+        };
+        e.emitter().emit(op, self.shape(), x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let t = self.x.tangent()?;
+        let x = || self.x.astype::<E::Grad>();
+        let r = || x().abs().astype::<E::Grad>();
+        Some(match self.op {
+            PartOp::Abs => (unit(x()).conj() * t).real().astype(),
+            PartOp::Angle => (x().conj() * t).imag().astype::<E::Grad>() / (r() * r()),
+            PartOp::Real => t.real().astype(),
+            PartOp::Imag => t.imag().astype(),
+        })
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T::Real, E>> {
+        Some(Expr(ExprData::new(Part {
+            op: self.op,
+            x: b.get(&self.x)?,
+        })))
+    }
+
+    // Conjugate Wirtinger convention, the gradient of `x` is `dL/dre(x) + i dL/dim(x)`. Imaginary
+    // part and phase of real values are constant, so they pass nothing back.
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        let complex = T::DTYPE.is_complex();
+        let x = || self.x.astype::<E::Grad>();
+        let r = || x().abs().astype::<E::Grad>();
+        let i = || full::<E::Grad, E>(self.shape().clone(), C128::i());
+        match self.op {
+            PartOp::Abs => self.x.accumulate(e, || grad * unit(x())),
+            PartOp::Angle if complex => self.x.accumulate(e, || grad * i() * x() / (r() * r())),
+            PartOp::Real => self.x.accumulate(e, || grad),
+            PartOp::Imag if complex => self.x.accumulate(e, || grad * i()),
+            PartOp::Angle | PartOp::Imag => {}
+        }
+    }
+}
+
+/// `x / |x|`, the derivative of `|x|`. Uses the subgradient 0 where `x` is 0.
+fn unit<E: Eval>(x: Expr<E::Grad, E>) -> Expr<E::Grad, E> {
+    let shape = x.shape().clone();
+    let r = x.clone().abs().astype::<E::Grad>();
+    let zero = r.clone().eq(zeros(shape.clone()));
+    // The division has to be safe as well, see `select`
+    let r = select(zero.clone(), ones(shape.clone()), r);
+    select(zero, zeros(shape), x / r)
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    fn part(self, op: PartOp) -> Expr<T::Real, E> {
+        Expr(ExprData::new(Part { op, x: self }))
+    }
+
+    /// Absolute value, magnitude for complex values
+    pub fn abs(self) -> Expr<T::Real, E> {
+        self.part(PartOp::Abs)
+    }
+    /// Phase of complex values, `0` or `pi` for real values
+    pub fn angle(self) -> Expr<T::Real, E> {
+        self.part(PartOp::Angle)
+    }
+    pub fn real(self) -> Expr<T::Real, E> {
+        self.part(PartOp::Real)
+    }
+    pub fn imag(self) -> Expr<T::Real, E> {
+        self.part(PartOp::Imag)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr, C64};
+    use crate::hl::test::TestEv;
+    use crate::ml::DType;
+    use crate::shape;
+
+    #[test]
+    fn test_part_types() {
+        let mut e = TestEv::new();
+        let z: Expr<C64, TestEv> = param(shape![3]);
+        assert!(z.requires_grad());
+
+        let r: Expr<f32, TestEv> = z.clone().abs();
+        let out = r.eval(&mut e);
+        assert_eq!(e.emitter().dtype(out), DType::F32);
+
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let y: Expr<C64, TestEv> = x.clone() * z.clone().conj();
+        let out = y.eval(&mut e);
+        assert_eq!(e.emitter().dtype(out), DType::C64);
+    }
+}
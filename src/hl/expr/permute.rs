@@ -0,0 +1,83 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug)]
+/// Reorders axes, axis `i` of the result is axis `axes[i]` of the input.
+struct Permute<T: Value, E: Eval> {
+    axes: Vec<usize>,
+    shape: Shape,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Permute<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let op = OpType::Permute {
+            axes: self.axes.clone(),
+        };
+        e.emitter().emit(op, &self.shape, x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        Some(self.x.tangent()?.permute(&self.axes))
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        let axes: Vec<usize> = [0]
+            .into_iter()
+            .chain(self.axes.iter().map(|a| a + 1))
+            .collect();
+        Some(b.get(&self.x)?.permute(&axes))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        let mut inverse = vec![0; self.axes.len()];
+        for (i, a) in self.axes.iter().enumerate() {
+            inverse[*a] = i;
+        }
+        self.x.accumulate(e, || grad.permute(&inverse));
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Reorders axes, axis `i` of the result is axis `axes[i]` of `self`
+    pub fn permute(self, axes: &[usize]) -> Expr<T, E> {
+        let mut sorted = axes.to_vec();
+        sorted.sort();
+        assert!(
+            sorted.iter().copied().eq(0..self.shape()[..].len()),
+            "{axes:?} is not a permutation of the axes of {:?}",
+            self.shape()
+        );
+        if sorted == axes {
+            return self;
+        }
+        let shape: Vec<usize> = axes.iter().map(|a| self.shape()[*a as isize]).collect();
+        Expr(ExprData::new(Permute {
+            axes: axes.to_vec(),
+            shape: shape.into(),
+            x: self,
+        }))
+    }
+
+    /// Swaps two axes
+    pub fn transpose(self, a: isize, b: isize) -> Expr<T, E> {
+        let mut axes: Vec<usize> = (0..self.shape()[..].len()).collect();
+        axes.swap(self.shape().wrap(a), self.shape().wrap(b));
+        self.permute(&axes)
+    }
+}
@@ -0,0 +1,323 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::{ones, zeros};
+use crate::hl::expr::select::select;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug, Clone, Copy)]
+enum ReduceOp {
+    /// y = sum(x)
+    Sum,
+    /// y = max(x), ties share the gradient evenly
+    Max,
+    /// y = min(x), ties share the gradient evenly
+    Min,
+    /// y = prod(x)
+    Prod,
+}
+
+#[derive(Debug)]
+/// Reduction over a single axis, removing it from the shape.
+struct Reduce<T: Value, E: Eval> {
+    op: ReduceOp,
+    axis: usize,
+    shape: Shape,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> Reduce<T, E> {
+    /// Repeats a reduced value over the reduced axis
+    fn spread<V: Value>(&self, y: Expr<V, E>) -> Expr<V, E> {
+        y.broadcast(self.axis, self.x.shape()[self.axis as isize])
+    }
+
+    /// Derivative of the result with respect to every element of `x`, `None` for sums
+    fn derivative(&self) -> Option<Expr<E::Grad, E>> {
+        let x = self.x.astype::<E::Grad>();
+        let shape = self.x.shape().clone();
+        Some(match self.op {
+            ReduceOp::Sum => return None,
+            ReduceOp::Max | ReduceOp::Min => {
+                let y = self.spread(x.clone().reduce(self.op, self.axis));
+                let hit = x.eq(y).astype::<E::Grad>();
+                hit.clone() / self.spread(hit.sum_axis(self.axis))
+            }
+            // Product of the other elements, without dividing by zeros
+            ReduceOp::Prod => {
+                let is_zero = x.clone().eq(zeros(shape.clone()));
+                let nonzero = select(is_zero.clone(), ones(shape.clone()), x);
+                let p = self.spread(nonzero.clone().reduce(ReduceOp::Prod, self.axis));
+                let count = self.spread(is_zero.astype::<E::Grad>().sum_axis(self.axis));
+                let single = select(
+                    count.clone().eq(ones(shape.clone())),
+                    p.clone(),
+                    zeros(shape.clone()),
+                );
+                select(
+                    count.eq(zeros(shape.clone())),
+                    p / nonzero,
+                    select(is_zero, single, zeros(shape)),
+                )
+            }
+        })
+    }
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Reduce<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let axis = self.axis;
+        let op = match self.op {
+            ReduceOp::Sum => OpType::Sum { axis },
+            ReduceOp::Max => OpType::Max { axis },
+            ReduceOp::Min => OpType::Min { axis },
+            ReduceOp::Prod => OpType::Prod { axis },
+        };
+        e.emitter().emit(op, &self.shape, x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let t = self.x.tangent()?;
+        match self.derivative() {
+            Some(d) => Some((t * d).sum_axis(self.axis)),
+            None => Some(t.sum_axis(self.axis)),
+        }
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        let x = b.get(&self.x)?;
+        Some(Expr(ExprData::new(Reduce {
+            op: self.op,
+            axis: self.axis + 1,
+            shape: self.shape.insert(0, b.size()),
+            x,
+        })))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        self.x.accumulate(e, || match self.derivative() {
+            Some(d) => self.spread(grad) * d.conj(),
+            None => self.spread(grad),
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ArgOp {
+    Max,
+    Min,
+}
+
+#[derive(Debug)]
+/// Index of the extreme element along a single axis, the first one on ties. Not differentiable.
+struct Arg<T: Value, E: Eval> {
+    op: ArgOp,
+    axis: usize,
+    shape: Shape,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<i64, E> for Arg<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    // Visitors are typed by the element type, so they can't continue into the input
+    fn accept(&self, v: &mut dyn Visitor<i64, E>) {}
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let axis = self.axis;
+        let op = match self.op {
+            ArgOp::Max => OpType::ArgMax { axis },
+            ArgOp::Min => OpType::ArgMin { axis },
+        };
+        e.emitter().emit(op, &self.shape, x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        None
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<i64, E>> {
+        Some(b.get(&self.x)?.arg(self.op, self.axis + 1))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {}
+}
+
+/// Axes of `shape` reduced by `axes`, sorted. Empty `axes` reduce everything.
+pub(crate) fn reduced_axes(shape: &Shape, axes: &[isize]) -> Vec<usize> {
+    let mut axes: Vec<usize> = match axes {
+        [] => (0..shape[..].len()).collect(),
+        axes => axes.iter().map(|a| shape.wrap(*a)).collect(),
+    };
+    axes.sort();
+    axes.dedup();
+    axes
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    fn reduce(self, op: ReduceOp, axis: usize) -> Expr<T, E> {
+        Expr(ExprData::new(Reduce {
+            op,
+            axis,
+            shape: self.shape().remove(axis as isize),
+            x: self,
+        }))
+    }
+
+    pub(crate) fn sum_axis(self, axis: usize) -> Expr<T, E> {
+        self.reduce(ReduceOp::Sum, axis)
+    }
+
+    /// Reduces every axis in `axes`, or all of them when it's empty. Negative axes count from the
+    /// end. With `keepdim` the reduced axes stay in the shape with size 1.
+    fn reduce_axes(self, op: ReduceOp, axes: &[isize], keepdim: bool) -> Expr<T, E> {
+        let axes = reduced_axes(self.shape(), axes);
+        let mut y = self;
+        if matches!(op, ReduceOp::Max | ReduceOp::Min) && axes.len() > 1 {
+            // Reduced axes are merged into one, so ties split the gradient over all of them at once
+            let kept: Vec<usize> = (0..y.shape()[..].len())
+                .filter(|a| !axes.contains(a))
+                .collect();
+            let mut merged: Vec<usize> = kept.iter().map(|a| y.shape()[*a as isize]).collect();
+            merged.push(axes.iter().map(|a| y.shape()[*a as isize]).product());
+            y = y.permute(&[&kept[..], &axes].concat()).reshape(merged);
+            y = y.reduce(op, kept.len());
+        } else {
+            for a in axes.iter().rev() {
+                y = y.reduce(op, *a);
+            }
+        }
+        if keepdim {
+            for a in axes {
+                y = y.broadcast(a, 1);
+            }
+        }
+        y
+    }
+
+    /// Sum over `axes`, see [`Expr::reduce_axes`]
+    pub fn sum(self, axes: &[isize], keepdim: bool) -> Expr<T, E> {
+        self.reduce_axes(ReduceOp::Sum, axes, keepdim)
+    }
+    /// Maximum over `axes`. Tied elements share the gradient evenly, also across several axes.
+    pub fn max(self, axes: &[isize], keepdim: bool) -> Expr<T, E> {
+        self.reduce_axes(ReduceOp::Max, axes, keepdim)
+    }
+    /// Minimum over `axes`. Tied elements share the gradient evenly, also across several axes.
+    pub fn min(self, axes: &[isize], keepdim: bool) -> Expr<T, E> {
+        self.reduce_axes(ReduceOp::Min, axes, keepdim)
+    }
+    /// Product over `axes`
+    pub fn prod(self, axes: &[isize], keepdim: bool) -> Expr<T, E> {
+        self.reduce_axes(ReduceOp::Prod, axes, keepdim)
+    }
+
+    fn arg(self, op: ArgOp, axis: usize) -> Expr<i64, E> {
+        let out: Expr<i64, E> = Expr(ExprData::new(Arg {
+            op,
+            axis,
+            shape: self.shape().remove(axis as isize),
+            x: self,
+        }));
+        out.set_requires_grad(false);
+        out
+    }
+
+    fn arg_keepdim(self, op: ArgOp, axis: isize, keepdim: bool) -> Expr<i64, E> {
+        let axis = self.shape().wrap(axis);
+        let y = self.arg(op, axis);
+        match keepdim {
+            true => y.broadcast(axis, 1),
+            false => y,
+        }
+    }
+
+    /// Index of the largest element along `axis`, the first one on ties
+    pub fn argmax(self, axis: isize, keepdim: bool) -> Expr<i64, E> {
+        self.arg_keepdim(ArgOp::Max, axis, keepdim)
+    }
+    /// Index of the smallest element along `axis`, the first one on ties
+    pub fn argmin(self, axis: isize, keepdim: bool) -> Expr<i64, E> {
+        self.arg_keepdim(ArgOp::Min, axis, keepdim)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::constant::ones;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::{arr1, arr2};
+
+    #[test]
+    fn test_shapes() {
+        let x: Expr<f32, TestEv> = param(shape![2, 3, 4]);
+        assert_eq!(x.clone().sum(&[1], false).shape(), &shape![2, 4]);
+        assert_eq!(x.clone().sum(&[-1, 0], true).shape(), &shape![1, 3, 1]);
+        assert_eq!(x.clone().max(&[], false).shape(), &shape![]);
+        assert_eq!(x.clone().argmax(1, true).shape(), &shape![2, 1, 4]);
+        assert!(!x.argmin(0, false).requires_grad());
+    }
+
+    #[test]
+    fn test_ties() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![2, 3]);
+        let max = x.clone().max(&[1], false);
+        let gmax = max.vjp(&mut e, ones(shape![2]), &x);
+        let prod = x.clone().prod(&[1], false);
+        let gprod = prod.vjp(&mut e, ones(shape![2]), &x);
+        let arg = x.clone().argmax(1, false);
+        // Split one axis at a time, a lone row maximum would get as much as two ties in another row
+        let all = x.clone().max(&[], false);
+        let gall = all.vjp(&mut e, ones(shape![]), &x);
+
+        let xb = x.eval(&mut e);
+        let bufs = [max, gmax, gprod, gall].map(|y| y.eval(&mut e));
+        let arg = arg.eval(&mut e);
+        let mut cpu = Cpu::new();
+        cpu.set(xb, arr2(&[[1.0, 3.0, 3.0], [0.0, 2.0, 5.0]]).into_dyn());
+
+        let b = e.emitter();
+        assert_eq!(cpu.get(b, bufs[0]), arr1(&[3.0, 5.0]).into_dyn());
+        assert_eq!(
+            cpu.get(b, bufs[1]),
+            arr2(&[[0.0, 0.5, 0.5], [0.0, 0.0, 1.0]]).into_dyn()
+        );
+        assert_eq!(
+            cpu.get(b, bufs[2]),
+            arr2(&[[9.0, 3.0, 3.0], [10.0, 0.0, 0.0]]).into_dyn()
+        );
+        assert_eq!(cpu.get(b, arg), arr1(&[1.0, 2.0]).into_dyn());
+
+        cpu.set(xb, arr2(&[[5.0, 5.0, 1.0], [5.0, 0.0, 2.0]]).into_dyn());
+        let third = (1.0f32 / 3.0) as f64;
+        assert_eq!(
+            cpu.get(b, bufs[3]),
+            arr2(&[[third, third, 0.0], [third, 0.0, 0.0]]).into_dyn()
+        );
+    }
+}
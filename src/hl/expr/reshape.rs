@@ -0,0 +1,70 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug)]
+/// Same elements in row-major order, with a new shape.
+struct Reshape<T: Value, E: Eval> {
+    shape: Shape,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Reshape<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let op = OpType::Reshape {
+            shape: self.shape.clone(),
+        };
+        e.emitter().emit(op, &self.shape, x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        Some(self.x.tangent()?.reshape(&self.shape))
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        let x = b.get(&self.x)?;
+        let shape = self.shape.insert(0, x.shape()[0]);
+        Some(x.reshape(shape))
+    }
+
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        self.x.accumulate(e, || grad.reshape(self.x.shape()));
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Same elements in row-major order with a new shape, which has to hold as many of them
+    pub fn reshape(self, shape: impl Into<Shape>) -> Expr<T, E> {
+        let shape = shape.into();
+        assert_eq!(
+            self.shape().prod(),
+            shape.prod(),
+            "Can't reshape {:?} to {shape:?}",
+            self.shape()
+        );
+        if self.shape() == &shape {
+            return self;
+        }
+        Expr(ExprData::new(Reshape { shape, x: self }))
+    }
+
+    /// Merges all axes into one
+    pub fn flatten(self) -> Expr<T, E> {
+        let n = self.shape().prod();
+        self.reshape([n])
+    }
+}
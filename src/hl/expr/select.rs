@@ -0,0 +1,140 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::{full, zeros};
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType};
+
+#[derive(Debug)]
+/// Elements of `a` where `cond` holds, of `b` elsewhere
+struct Select<T: Value, E: Eval> {
+    shape: Shape,
+    cond: Expr<bool, E>,
+    a: Expr<T, E>,
+    b: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Select<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.a.accept(v);
+        self.b.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.cond);
+        f(&self.a);
+        f(&self.b);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let srcs = [self.cond.eval(e), self.a.eval(e), self.b.eval(e)];
+        e.emitter().emit_n(OpType::Select, &self.shape, &srcs)
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let (ta, tb) = (self.a.tangent(), self.b.tangent());
+        if ta.is_none() && tb.is_none() {
+            return None;
+        }
+        let zero = || zeros(self.shape.clone());
+        Some(select(
+            self.cond.clone(),
+            ta.unwrap_or_else(zero),
+            tb.unwrap_or_else(zero),
+        ))
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        if b.get(&self.cond).is_none() && b.get(&self.a).is_none() && b.get(&self.b).is_none() {
+            return None;
+        }
+        Some(select(
+            b.get_or_broadcast(&self.cond),
+            b.get_or_broadcast(&self.a),
+            b.get_or_broadcast(&self.b),
+        ))
+    }
+
+    // Selecting instead of multiplying by the mask keeps infinities of the other branch out
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        let zero = || zeros(self.shape.clone());
+        self.a
+            .accumulate(e, || select(self.cond.clone(), grad.clone(), zero()));
+        self.b
+            .accumulate(e, || select(self.cond.clone(), zero(), grad));
+    }
+}
+
+/// Elements of `a` where `cond` holds, of `b` elsewhere. Gradients only reach the selected side.
+///
+/// Both sides are still evaluated everywhere. Guarding a `log` with
+/// `select(x.gt(&eps), x.log(), zeros)` still gives NaN gradients where `x` is 0, the input of
+/// the `log` has to be made safe as well.
+pub fn select<T: Value, E: Eval>(
+    cond: Expr<bool, E>,
+    a: impl Into<Expr<T, E>>,
+    b: impl Into<Expr<T, E>>,
+) -> Expr<T, E> {
+    let (a, b) = (a.into(), b.into());
+    assert_eq!(
+        cond.shape(),
+        a.shape(),
+        "Condition and values differ in shape"
+    );
+    assert_eq!(a.shape(), b.shape(), "Selected values differ in shape");
+    Expr(ExprData::new(Select {
+        shape: a.shape().clone(),
+        cond,
+        a,
+        b,
+    }))
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Limits elements to `[min, max]`. Gradients pass where `x` is within the bounds, including
+    /// the bounds themselves.
+    pub fn clamp(self, min: f64, max: f64) -> Expr<T, E> {
+        let lo = full::<T, E>(self.shape().clone(), min);
+        let hi = full::<T, E>(self.shape().clone(), max);
+        let upper = select(self.clone().gt(&hi), hi, &self);
+        select(self.lt(&lo), lo, upper)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::constant::{constant, zeros};
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::select::select;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::arr1;
+
+    #[test]
+    fn test_select() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let z: Expr<f32, TestEv> = zeros(shape![3]);
+
+        // Safe log, the input is replaced as well as the output
+        let pos = x.clone().gt(&z);
+        let safe = select(pos.clone(), &x, constant(shape![3], vec![1.0]));
+        let y = select(pos, safe.log(), z);
+        let g = y.vjp(&mut e, constant(shape![3], vec![1.0]), &x);
+
+        let (xb, yb, gb) = (x.eval(&mut e), y.eval(&mut e), g.eval(&mut e));
+        let mut cpu = Cpu::new();
+        cpu.set(xb, arr1(&[0.0, 1.0, 2.0]).into_dyn());
+        let b = e.emitter();
+        assert_eq!(
+            cpu.get(b, yb),
+            arr1(&[0.0, 0.0, 2f32.ln() as f64]).into_dyn()
+        );
+        assert_eq!(cpu.get(b, gb), arr1(&[0.0, 1.0, 0.5]).into_dyn());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::zeros;
+use crate::hl::expr::index::indices;
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Value, Visitor};
+use crate::hl::shape::Shape;
+use crate::ml::{BufId, OpType, PadKind};
+use std::ops::{Bound, RangeBounds};
+
+#[derive(Debug)]
+/// Every `step`-th element along an axis, from `start` on.
+struct Slice<T: Value, E: Eval> {
+    axis: usize,
+    start: usize,
+    step: usize,
+    shape: Shape,
+    x: Expr<T, E>,
+}
+
+impl<T: Value, E: Eval> Slice<T, E> {
+    fn len(&self) -> usize {
+        self.shape[self.axis as isize]
+    }
+}
+
+impl<T: Value, E: Eval> ExprImpl<T, E> for Slice<T, E> {
+    fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    fn accept(&self, v: &mut dyn Visitor<T, E>) {
+        self.x.accept(v);
+    }
+
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
+    fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let op = OpType::Slice {
+            axis: self.axis,
+            start: self.start,
+            len: self.len(),
+            step: self.step,
+        };
+        e.emitter().emit(op, &self.shape, x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let t = self.x.tangent()?;
+        Some(t.strided(self.axis, self.start, self.len(), self.step))
+    }
+
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        let x = b.get(&self.x)?;
+        Some(x.strided(self.axis + 1, self.start, self.len(), self.step))
+    }
+
+    // Elements outside of the slice get no gradient
+    fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
+        let (axis, n) = (self.axis as isize, self.x.shape()[self.axis as isize]);
+        self.x.accumulate(e, || {
+            if self.step == 1 {
+                let after = n - self.start - self.len();
+                return grad.pad(axis, self.start, after, PadKind::Zero);
+            }
+            let idx = (0..self.len()).map(|i| self.start + i * self.step);
+            zeros(self.x.shape().clone()).index_add(axis, indices(idx), grad)
+        });
+    }
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    fn strided(self, axis: usize, start: usize, len: usize, step: usize) -> Expr<T, E> {
+        if step == 1 && len == self.shape()[axis as isize] {
+            return self;
+        }
+        Expr(ExprData::new(Slice {
+            axis,
+            start,
+            step,
+            shape: self.shape().set(axis as isize, len),
+            x: self,
+        }))
+    }
+
+    /// `len` elements along `axis`, from `start` on
+    pub fn narrow(self, axis: isize, start: usize, len: usize) -> Expr<T, E> {
+        let axis = self.shape().wrap(axis);
+        let n = self.shape()[axis as isize];
+        assert!(
+            start + len <= n,
+            "Slice {start}..{} out of bounds of axis {axis} in {:?}",
+            start + len,
+            self.shape()
+        );
+        self.strided(axis, start, len, 1)
+    }
+
+    /// Every `step`-th element of `range` along `axis`, like `x[start:end:step]` in NumPy. Negative
+    /// bounds count from the end and bounds past the ends are clamped.
+    pub fn slice(self, axis: isize, range: impl RangeBounds<isize>, step: usize) -> Expr<T, E> {
+        assert!(step > 0, "Slice step has to be positive");
+        let axis = self.shape().wrap(axis);
+        let n = self.shape()[axis as isize] as isize;
+        let wrap = |i: isize| if i < 0 { n + i } else { i };
+        let clamp = |i: isize| i.clamp(0, n) as usize;
+        let start = match range.start_bound() {
+            Bound::Included(i) => clamp(wrap(*i)),
+            Bound::Excluded(i) => clamp(wrap(*i) + 1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(i) => clamp(wrap(*i) + 1),
+            Bound::Excluded(i) => clamp(wrap(*i)),
+            Bound::Unbounded => n as usize,
+        };
+        let len = end.saturating_sub(start).div_ceil(step);
+        self.strided(axis, start, len, step)
+    }
+
+    /// Element `i` along `axis`, which is removed. Negative `i` counts from the end.
+    pub fn index(self, axis: isize, i: isize) -> Expr<T, E> {
+        let n = self.shape()[axis];
+        assert!(
+            -(n as isize) <= i && i < n as isize,
+            "Index {i} out of bounds of axis {axis} in {:?}",
+            self.shape()
+        );
+        let i = (if i < 0 { i + n as isize } else { i }) as usize;
+        let shape = self.shape().remove(axis);
+        self.narrow(axis, i, 1).reshape(shape)
+    }
+}
@@ -0,0 +1,187 @@
+use crate::hl::expr::constant::full;
+use crate::hl::expr::custom::custom_op;
+use crate::hl::expr::reduce::reduced_axes;
+use crate::hl::expr::{Eval, Expr, Value};
+use crate::hl::shape::Shape;
+
+/// Axes counted from the end, so they stay valid when [`vmap`](crate::hl::expr::batch::vmap)
+/// adds a leading batch axis
+fn from_end(shape: &Shape, axes: &[isize]) -> Vec<isize> {
+    let n = shape[..].len() as isize;
+    reduced_axes(shape, axes)
+        .into_iter()
+        .map(|a| a as isize - n)
+        .collect()
+}
+
+impl<T: Value, E: Eval> Expr<T, E> {
+    /// Repeats the result of reducing `axes` of `shape` back to `shape`
+    fn unreduce(self, axes: &[isize], shape: &Shape) -> Expr<T, E> {
+        let mut y = self;
+        for a in reduced_axes(shape, axes) {
+            y = y.broadcast(a, shape[a as isize]);
+        }
+        y
+    }
+
+    /// Inserts the reduced axes back with size 1
+    fn keepdim(self, axes: &[isize], shape: &Shape, keepdim: bool) -> Expr<T, E> {
+        let mut y = self;
+        if keepdim {
+            for a in reduced_axes(shape, axes) {
+                y = y.broadcast(a, 1);
+            }
+        }
+        y
+    }
+
+    /// Number of elements reduced together
+    fn count(&self, axes: &[isize]) -> usize {
+        let shape = self.shape();
+        reduced_axes(shape, axes)
+            .into_iter()
+            .map(|a| shape[a as isize])
+            .product()
+    }
+
+    /// Mean over `axes`, all of them when empty
+    pub fn mean(self, axes: &[isize], keepdim: bool) -> Expr<T, E> {
+        let n = self.count(axes);
+        let y = self.sum(axes, keepdim);
+        let scale = full(y.shape().clone(), 1.0 / n as f64);
+        y * scale
+    }
+
+    /// Variance over `axes`, dividing by `n - correction`. `correction` of 1 gives the unbiased
+    /// estimate.
+    pub fn var(self, axes: &[isize], correction: usize, keepdim: bool) -> Expr<T, E> {
+        let shape = self.shape().clone();
+        let n = self.count(axes);
+        let centered = self.clone() - self.mean(axes, false).unreduce(axes, &shape);
+        let y = (centered.clone() * centered).sum(axes, keepdim);
+        let scale = full(y.shape().clone(), 1.0 / (n as f64 - correction as f64));
+        y * scale
+    }
+
+    /// Standard deviation over `axes`, see [`Expr::var`]
+    pub fn std(self, axes: &[isize], correction: usize, keepdim: bool) -> Expr<T, E> {
+        self.var(axes, correction, keepdim).sqrt()
+    }
+
+    /// `x - max(x)` over `axes`, with the maximum treated as a constant. Leaves softmax and
+    /// logsumexp unchanged, but keeps `exp` from overflowing.
+    fn shifted(self, axes: &[isize]) -> Expr<T, E> {
+        let shape = self.shape().clone();
+        let m = self
+            .clone()
+            .detach()
+            .max(axes, false)
+            .unreduce(axes, &shape);
+        self - m
+    }
+
+    fn softmax_over(self, axes: &[isize]) -> Expr<T, E> {
+        let shape = self.shape().clone();
+        let e = self.shifted(axes).exp();
+        e.clone() / e.sum(axes, false).unreduce(axes, &shape)
+    }
+
+    /// `ln(sum(e^x))` over `axes`. The gradient is the softmax of `x`.
+    pub fn logsumexp(self, axes: &[isize], keepdim: bool) -> Expr<T, E> {
+        let shape = self.shape().clone();
+        let tail = from_end(&shape, axes);
+        let bwd = tail.clone();
+        let y = custom_op(
+            self,
+            |x| {
+                let m = x.clone().detach().max(&tail, false);
+                m + x.shifted(&tail).exp().sum(&tail, false).log()
+            },
+            move |x, g| g.unreduce(&bwd, x.shape()) * x.astype::<E::Grad>().softmax_over(&bwd),
+        );
+        y.keepdim(axes, &shape, keepdim)
+    }
+
+    /// `e^x / sum(e^x)` along `axis`
+    pub fn softmax(self, axis: isize) -> Expr<T, E> {
+        let tail = from_end(self.shape(), &[axis]);
+        let bwd = tail.clone();
+        custom_op(
+            self,
+            |x| x.softmax_over(&tail),
+            move |x, g| {
+                let y = x.astype::<E::Grad>().softmax_over(&bwd);
+                let dot = (g.clone() * &y).sum(&bwd, false).unreduce(&bwd, x.shape());
+                y * (g - dot)
+            },
+        )
+    }
+
+    /// `ln(softmax(x))` along `axis`, without going through the softmax
+    pub fn log_softmax(self, axis: isize) -> Expr<T, E> {
+        let tail = from_end(self.shape(), &[axis]);
+        let bwd = tail.clone();
+        custom_op(
+            self,
+            |x| {
+                let shape = x.shape().clone();
+                let s = x.shifted(&tail);
+                let lse = s.clone().exp().sum(&tail, false).log();
+                s - lse.unreduce(&tail, &shape)
+            },
+            move |x, g| {
+                let y = x.astype::<E::Grad>().softmax_over(&bwd);
+                let total = g.clone().sum(&bwd, false).unreduce(&bwd, x.shape());
+                g - y * total
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::arr2;
+
+    #[test]
+    fn test_stats() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![2, 3]);
+        let outs = [
+            x.clone().mean(&[], false),
+            x.clone().var(&[1], 1, false),
+            x.clone().std(&[1], 0, false),
+            x.clone().logsumexp(&[1], false),
+        ];
+        assert_eq!(x.clone().mean(&[0], true).shape(), &shape![1, 3]);
+        assert_eq!(x.clone().logsumexp(&[1], true).shape(), &shape![2, 1]);
+
+        let xb = x.eval(&mut e);
+        let bufs = outs.map(|y| y.eval(&mut e));
+        let soft = x.clone().softmax(-1).eval(&mut e);
+        let log_soft = x.log_softmax(-1).eval(&mut e);
+
+        let mut cpu = Cpu::new();
+        cpu.set(
+            xb,
+            arr2(&[[1.0, 2.0, 3.0], [1000.0, 1000.0, 1000.0]]).into_dyn(),
+        );
+        let b = e.emitter();
+        // Results are rounded to f32
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-5 * b.abs().max(1.0);
+        assert!(close(cpu.get(b, bufs[0]).sum(), 501.0));
+        let var = cpu.get(b, bufs[1]);
+        assert!(close(var[0], 1.0) && close(var[1], 0.0));
+        assert!(close(cpu.get(b, bufs[2])[0], (2.0f64 / 3.0).sqrt()));
+
+        // Large inputs don't overflow
+        let lse = cpu.get(b, bufs[3]);
+        assert!(close(lse[1], 1000.0 + 3f64.ln()));
+        assert!(cpu.get(b, soft).iter().all(|v| v.is_finite()));
+        assert!(close(cpu.get(b, log_soft)[[1, 0]], -3f64.ln()));
+    }
+}
@@ -1,11 +1,12 @@
-use crate::hl::expr::param::zero;
-use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Ten, Value, Visitor};
+use crate::hl::expr::batch::Batch;
+use crate::hl::expr::constant::{full, zeros};
+use crate::hl::expr::{Eval, Expr, ExprData, ExprImpl, Node, Ten, Value, Visitor};
 use crate::hl::shape::Shape;
 use crate::ml::{BufId, OpType};
 use std::ops::Neg;
 use num::traits::{Inv, Pow};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Unary operation. Executed on each scalar, candidate for fusion.
 enum UnOp {
     /// Negation y = -x
@@ -18,44 +19,131 @@ enum UnOp {
     Log,
     /// Greater-than-zero y = x > 0
     Gtz,
+    /// Complex conjugate y = re(x) - i im(x)
+    Conj,
+    /// Square root y = x^(1/2)
+    Sqrt,
+    /// Reciprocal square root y = x^(-1/2)
+    Rsqrt,
+    Sin,
+    Cos,
+    Tanh,
+    /// Sign of real values y = -1, 0 or 1
+    Sign,
+    Floor,
+    Ceil,
+    /// Rounds half to even
+    Round,
+    /// Gauss error function
+    Erf,
+    /// y = e^x - 1, accurate near 0
+    Expm1,
+    /// y = ln(1 + x), accurate near 0
+    Log1p,
 }
 
 #[derive(Debug)]
 struct Un<T: Value, E: Eval> {
     op: UnOp,
+    shape: Shape,
     x: Expr<T, E>,
 }
 
+impl<T: Value, E: Eval> Un<T, E> {
+    /// Derivative of the op at `x`, `None` for piecewise constant ops
+    fn derivative(&self) -> Option<Expr<E::Grad, E>> {
+        let x = || self.x.astype::<E::Grad>();
+        let c = |v: f64| full::<E::Grad, E>(self.shape.clone(), v);
+        Some(match self.op {
+            UnOp::Rec => {
+                let y = x().inv();
+                -(y.clone() * y)
+            }
+            UnOp::Exp | UnOp::Expm1 => x().exp(),
+            UnOp::Log => x().inv(),
+            UnOp::Sqrt => c(0.5) / x().sqrt(),
+            UnOp::Rsqrt => c(-0.5) * x().rsqrt() / x(),
+            UnOp::Sin => x().cos(),
+            UnOp::Cos => -x().sin(),
+            UnOp::Tanh => {
+                let y = x().tanh();
+                c(1.0) - y.clone() * y
+            }
+            UnOp::Erf => c(std::f64::consts::FRAC_2_SQRT_PI) * (-(x() * x())).exp(),
+            UnOp::Log1p => (c(1.0) + x()).inv(),
+            UnOp::Gtz | UnOp::Sign | UnOp::Floor | UnOp::Ceil | UnOp::Round => return None,
+            // Conjugate is not holomorphic, both have their own rules
+            UnOp::Neg | UnOp::Conj => unreachable!(),
+        })
+    }
+}
+
 impl<T: Value, E: Eval> ExprImpl<T, E> for Un<T, E> {
     fn shape(&self) -> &Shape {
-        &self.shape()
+        &self.shape
     }
 
     fn accept(&self, v: &mut dyn Visitor<T, E>) {
         self.x.accept(v);
     }
 
+    fn inputs(&self, f: &mut dyn FnMut(&dyn Node<E>)) {
+        f(&self.x);
+    }
+
     fn eval(&self, id: u64, e: &mut E) -> BufId {
+        let x = self.x.eval(e);
+        let op = match self.op {
+            UnOp::Neg => OpType::Neg,
+            UnOp::Rec => OpType::Rec,
+            UnOp::Exp => OpType::Exp,
+            UnOp::Log => OpType::Log,
+            UnOp::Gtz => OpType::Gtz,
+            UnOp::Conj => OpType::Conj,
+            UnOp::Sqrt => OpType::Sqrt,
+            UnOp::Rsqrt => OpType::Rsqrt,
+            UnOp::Sin => OpType::Sin,
+            UnOp::Cos => OpType::Cos,
+            UnOp::Tanh => OpType::Tanh,
+            UnOp::Sign => OpType::Sign,
+            UnOp::Floor => OpType::Floor,
+            UnOp::Ceil => OpType::Ceil,
+            UnOp::Round => OpType::Round,
+            UnOp::Erf => OpType::Erf,
+            UnOp::Expm1 => OpType::Expm1,
+            UnOp::Log1p => OpType::Log1p,
+        };
+        e.emitter().emit(op, &self.shape, x, BufId::default())
+    }
+
+    fn jvp(&self, e: &mut E) -> Option<Expr<E::Grad, E>> {
+        let t = self.x.tangent()?;
         match self.op {
-            UnOp::Neg => {
-                let x = self.x.eval(e);
-                e.emitter()
-                    .emit(OpType::Neg, self.shape(), x, BufId::default())
-            }
-            _ => todo!(),
+            UnOp::Neg => Some(-t),
+            UnOp::Conj => Some(t.conj()),
+            _ => Some(t * self.derivative()?),
         }
     }
 
+    fn vmap(&self, b: &mut Batch) -> Option<Expr<T, E>> {
+        let x = b.get(&self.x)?;
+        Some(Expr(ExprData::new(Un {
+            op: self.op.clone(),
+            shape: x.shape().clone(),
+            x,
+        })))
+    }
+
+    // Gradients of complex values follow the conjugate Wirtinger convention, `dL/dre + i dL/dim`.
+    // For holomorphic ops that is the incoming gradient times the conjugated derivative.
     fn backward(&self, e: &mut E, grad: Expr<E::Grad, E>) {
         match self.op {
-            UnOp::Neg => self.x.backward(e, || -grad),
-            UnOp::Rec => self.x.backward(e, || {
-                let xt = self.x.astype();
-                -grad * &xt * xt
+            UnOp::Neg => self.x.accumulate(e, || -grad),
+            UnOp::Conj => self.x.accumulate(e, || grad.conj()),
+            _ => self.x.accumulate(e, || match self.derivative() {
+                Some(d) => grad * d.conj(),
+                None => zeros(grad.shape().clone()),
             }),
-            UnOp::Exp => self.x.backward(e, || grad * self.x.astype()),
-            UnOp::Log => self.x.backward(e, || grad / self.x.astype()),
-            UnOp::Gtz => self.x.backward(e, || zero::<T, E>(grad.shape().clone())),
         }
     }
 }
@@ -64,10 +152,7 @@ impl<T: Value, E: Eval> Neg for Expr<T, E> {
     type Output = Expr<T, E>;
 
     fn neg(self) -> Self::Output {
-        Expr(ExprData::new(Un {
-            op: UnOp::Neg,
-            x: self,
-        }))
+        self.un(UnOp::Neg)
     }
 }
 
@@ -75,30 +160,75 @@ impl<T: Value, E: Eval> Inv for Expr<T, E> {
     type Output = Expr<T, E>;
 
     fn inv(self) -> Self::Output {
-        Expr(ExprData::new(Un {
-            op: UnOp::Rec,
-            x: self,
-        }))
+        self.un(UnOp::Rec)
     }
 }
 
 impl<T: Value, E: Eval> Expr<T, E> {
-    pub fn exp(self) -> Expr<T, E> {
+    fn un(self, op: UnOp) -> Expr<T, E> {
         Expr(ExprData::new(Un {
-            op: UnOp::Exp,
+            op,
+            shape: self.shape().clone(),
             x: self,
         }))
     }
+
+    pub fn exp(self) -> Expr<T, E> {
+        self.un(UnOp::Exp)
+    }
     pub fn log(self) -> Expr<T, E> {
-        Expr(ExprData::new(Un {
-            op: UnOp::Log,
-            x: self,
-        }))
+        self.un(UnOp::Log)
     }
-    fn gtz(self) -> Expr<T, E> {
-        Expr(ExprData::new(Un {
-            op: UnOp::Gtz,
-            x: self,
-        }))
+    /// Complex conjugate, no-op for real values
+    pub fn conj(self) -> Expr<T, E> {
+        if !T::DTYPE.is_complex() {
+            return self;
+        }
+        self.un(UnOp::Conj)
+    }
+    pub(crate) fn gtz(self) -> Expr<T, E> {
+        self.un(UnOp::Gtz)
+    }
+    pub fn sqrt(self) -> Expr<T, E> {
+        self.un(UnOp::Sqrt)
+    }
+    pub fn rsqrt(self) -> Expr<T, E> {
+        self.un(UnOp::Rsqrt)
+    }
+    pub fn sin(self) -> Expr<T, E> {
+        self.un(UnOp::Sin)
+    }
+    pub fn cos(self) -> Expr<T, E> {
+        self.un(UnOp::Cos)
+    }
+    pub fn tanh(self) -> Expr<T, E> {
+        self.un(UnOp::Tanh)
+    }
+    /// Sign of real values, `x / |x|` for complex values
+    pub fn sign(self) -> Expr<T, E> {
+        if T::DTYPE.is_complex() {
+            return self.clone() / self.abs().astype::<T>();
+        }
+        self.un(UnOp::Sign)
+    }
+    pub fn floor(self) -> Expr<T, E> {
+        self.un(UnOp::Floor)
+    }
+    pub fn ceil(self) -> Expr<T, E> {
+        self.un(UnOp::Ceil)
+    }
+    /// Rounds to the nearest integer, half to even
+    pub fn round(self) -> Expr<T, E> {
+        self.un(UnOp::Round)
+    }
+    /// Error function, real values only
+    pub fn erf(self) -> Expr<T, E> {
+        self.un(UnOp::Erf)
+    }
+    pub fn expm1(self) -> Expr<T, E> {
+        self.un(UnOp::Expm1)
+    }
+    pub fn log1p(self) -> Expr<T, E> {
+        self.un(UnOp::Log1p)
     }
 }
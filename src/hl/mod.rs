@@ -1,14 +1,62 @@
 pub mod expr;
 pub mod module;
+pub mod optim;
 pub mod shape;
 
 #[cfg(test)]
 mod test {
+    use crate::hl::expr::constant::ones;
+    use crate::hl::expr::diff::{grad, hessian, hvp, jacobian, jvp, value_and_grad, vjp};
     use crate::hl::expr::param::param;
-    use crate::hl::expr::{Eval, Expr, Value};
+    use crate::hl::expr::{topo, Eval, Expr, Node, Value};
     use crate::hl::module::Module;
+    use crate::ll::cpu::Cpu;
     use crate::ml::MLBuilder;
     use crate::shape;
+    use ndarray::ArrayD;
+    use num::traits::Inv;
+    use std::marker::PhantomData;
+
+    #[derive(Debug)]
+    /// Gradients are `G`, complex graphs need a complex one
+    pub(crate) struct TestEv<G = f32> {
+        id: u64,
+        bldr: MLBuilder,
+        _g: PhantomData<G>,
+    }
+
+    impl TestEv {
+        pub(crate) fn new() -> Self {
+            Self::with_grad()
+        }
+    }
+
+    impl<G: Value> TestEv<G> {
+        pub(crate) fn with_grad() -> Self {
+            TestEv {
+                id: 0,
+                bldr: MLBuilder::new(),
+                _g: PhantomData,
+            }
+        }
+    }
+
+    impl<G: Value> Eval for TestEv<G> {
+        type Grad = G;
+
+        fn mkid(&mut self) -> u64 {
+            self.id += 1;
+            self.id
+        }
+
+        fn enter(&self, id: u64) {}
+
+        fn exit(&self, id: u64) {}
+
+        fn emitter(&mut self) -> &mut MLBuilder {
+            &mut self.bldr
+        }
+    }
 
     #[test]
     fn test_expr_bldr() {
@@ -19,40 +67,273 @@ mod test {
             move |i| i * &weight + &bias
         }
 
-        #[derive(Debug)]
-        struct TestEv {
-            id: u64,
-            bldr: MLBuilder,
+        let mut e = TestEv::new();
+        let module = model::<f32, TestEv>();
+        let out = module.forward(param(shape![0]));
+        let out = out.eval(&mut e);
+        println!("{e:#?}")
+    }
+
+    /// Evaluates `outs` on the [`Cpu`] backend, with params set to the flat values in `inputs`
+    pub(crate) fn run(
+        e: &mut TestEv,
+        inputs: &[(&Expr<f32, TestEv>, &[f64])],
+        outs: &[Expr<f32, TestEv>],
+    ) -> Vec<Vec<f64>> {
+        let xbufs: Vec<_> = inputs.iter().map(|(x, _)| x.eval(e)).collect();
+        let obufs: Vec<_> = outs.iter().map(|y| y.eval(e)).collect();
+        let mut cpu = Cpu::new();
+        for (b, (x, v)) in xbufs.into_iter().zip(inputs) {
+            let v = ArrayD::from_shape_vec(&x.shape()[..], v.to_vec()).unwrap();
+            cpu.set(b, v);
         }
+        let bld = e.emitter();
+        obufs
+            .into_iter()
+            .map(|b| cpu.get(bld, b).iter().copied().collect())
+            .collect()
+    }
 
-        impl Eval for TestEv {
-            type Grad = f32;
+    /// Whether `n` is part of the graph of `g`
+    fn depends(g: &Expr<f32, TestEv>, n: &Expr<f32, TestEv>) -> bool {
+        topo::<TestEv>(g).iter().any(|m| m.key() == n.key())
+    }
 
-            fn mkid(&mut self) -> u64 {
-                self.id += 1;
-                self.id
-            }
+    #[test]
+    fn test_backward() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let w: Expr<f32, TestEv> = param(shape![3]);
+        let unused: Expr<f32, TestEv> = param(shape![3]);
 
-            fn grad(&self) -> bool {
-                todo!()
-            }
+        let y = x.clone() * &w + &w;
+        y.backward(&mut e);
 
-            fn enter(&self, id: u64) {}
+        assert!(y.grad().is_some());
+        assert!(x.grad().is_some());
+        assert!(w.grad().is_some());
+        assert!(unused.grad().is_none());
 
-            fn exit(&self, id: u64) {}
+        // dy/dx = w and dy/dw = x + 1
+        let (gx, gw) = (x.grad().unwrap(), w.grad().unwrap());
+        assert!(depends(&gx, &w) && !depends(&gx, &x));
+        assert!(depends(&gw, &x) && !depends(&gw, &w));
 
-            fn emitter(&mut self) -> &mut MLBuilder {
-                &mut self.bldr
-            }
+        // Gradients are ordinary expressions, lowered like the forward graph
+        let (xv, wv) = ([1.0, 2.0, -3.0], [0.5, -1.0, 4.0]);
+        let g = run(&mut e, &[(&x, &xv), (&w, &wv)], &[gx, gw]);
+        assert_eq!(g[0], wv);
+        assert_eq!(g[1], [2.0, 3.0, -2.0]);
+
+        y.zero_grad();
+        assert!(x.grad().is_none());
+        assert!(w.grad().is_none());
+    }
+
+    #[test]
+    fn test_backward_repeated() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![1]);
+        let w: Expr<f32, TestEv> = param(shape![1]);
+
+        // Each pass adds dy/dx = w once
+        let y = x.clone() * &w;
+        y.backward(&mut e);
+        y.backward(&mut e);
+        let g = run(&mut e, &[(&x, &[1.0]), (&w, &[5.0])], &[x.grad().unwrap()]);
+        assert_eq!(g, [[10.0]]);
+        y.zero_grad();
+
+        // Passes sharing `h` don't push its gradient from the first pass again: 2w + 2xw^2
+        let h = x.clone() * &w;
+        (h.clone() + &h).backward(&mut e);
+        (h.clone() * &h).backward(&mut e);
+        let g = run(&mut e, &[(&x, &[1.0]), (&w, &[5.0])], &[x.grad().unwrap()]);
+        assert_eq!(g, [[60.0]]);
+    }
+
+    #[test]
+    fn test_backward_un() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let xv = [0.5, 1.0, -2.0];
+
+        // d exp(x) = exp(x), d(1/x) = -1/x^2
+        let grad_of = |y: Expr<f32, TestEv>, e: &mut TestEv| {
+            y.backward(e);
+            let g = x.grad().unwrap();
+            y.zero_grad();
+            g
+        };
+        let gexp = grad_of(x.clone().exp(), &mut e);
+        let grec = grad_of(x.clone().inv(), &mut e);
+        assert!(format!("{gexp:?}").contains("Exp"));
+        assert!(format!("{grec:?}").contains("Rec"));
+
+        let g = run(&mut e, &[(&x, &xv)], &[gexp, grec]);
+        for i in 0..3 {
+            assert!((g[0][i] - xv[i].exp()).abs() < 1e-6);
+            assert!((g[1][i] + 1.0 / (xv[i] * xv[i])).abs() < 1e-6);
         }
+    }
+
+    #[test]
+    fn test_jvp() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let w: Expr<f32, TestEv> = param(shape![3]);
 
-        let mut e = TestEv {
-            id: 0,
-            bldr: MLBuilder::new(),
+        let y = x.clone() * &w + x.clone().exp();
+        x.set_tangent(ones(shape![3]));
+        y.jvp(&mut e);
+
+        // Only nodes downstream of the seed carry a tangent
+        assert!(w.tangent().is_none());
+        assert!(y.tangent().is_some());
+
+        let (xv, wv) = ([0.5, -1.0, 2.0], [1.5, 2.0, -0.5]);
+        let t = run(&mut e, &[(&x, &xv), (&w, &wv)], &[y.tangent().unwrap()]);
+        for i in 0..3 {
+            assert!((t[0][i] - (wv[i] + xv[i].exp())).abs() < 1e-5);
+        }
+
+        y.zero_tangent();
+        assert!(x.tangent().is_none());
+        assert!(y.tangent().is_none());
+    }
+
+    /// Runs backward on `y`, then again on the gradient of `x`, returning the second pass result
+    fn second_order(
+        y: Expr<f32, TestEv>,
+        x: &Expr<f32, TestEv>,
+        e: &mut TestEv,
+    ) -> Option<Expr<f32, TestEv>> {
+        y.backward(e);
+        let gx = x.grad().unwrap();
+        y.zero_grad();
+        gx.zero_grad();
+        gx.backward(e);
+        x.grad()
+    }
+
+    /// Evaluates the second derivative of `f` at `xv`, see [`second_order`]
+    fn second_values(f: impl Fn(Expr<f32, TestEv>) -> Expr<f32, TestEv>, xv: &[f64]) -> Vec<f64> {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![xv.len()]);
+        let d2 = second_order(f(x.clone()), &x, &mut e).unwrap();
+        run(&mut e, &[(&x, xv)], &[d2]).remove(0)
+    }
+
+    fn assert_close(a: &[f64], b: impl IntoIterator<Item = f64>) {
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-4 * b.abs().max(1.0), "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn test_grad_of_grad() {
+        let mut e = TestEv::new();
+
+        // d(xw)/dx = w, which no longer depends on x
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let w: Expr<f32, TestEv> = param(shape![3]);
+        assert!(second_order(x.clone() * &w, &x, &mut e).is_none());
+        assert!(w.grad().is_some());
+
+        let xv = [0.5, -1.0, 2.0];
+        assert_close(&second_values(|x| x.clone() * &x, &xv), [2.0; 3]);
+        assert_close(
+            &second_values(|x| x.inv(), &xv),
+            xv.map(|x| 2.0 / (x * x * x)),
+        );
+    }
+
+    #[test]
+    fn test_grad_of_grad_exp_log() {
+        let xv = [0.5, 1.0, 2.0];
+        assert_close(&second_values(|x| x.exp(), &xv), xv.map(f64::exp));
+        assert_close(&second_values(|x| x.log(), &xv), xv.map(|x| -1.0 / (x * x)));
+    }
+
+    #[test]
+    fn test_jacobian_hessian() {
+        let mut e = TestEv::new();
+        let w: Expr<f32, TestEv> = param(shape![3]);
+        let f = |x: Expr<f32, TestEv>| x.clone() * &x + &w;
+
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        let jac = jacobian(&mut e, &f, &x);
+        assert_eq!(jac.len(), 3);
+        assert!(x.grad().is_none());
+        let xv = [0.5, -1.0, 2.0];
+        let rows = run(&mut e, &[(&x, &xv), (&w, &[0.0; 3])], &jac);
+        for (i, row) in rows.iter().enumerate() {
+            let mut diag = [0.0; 3];
+            diag[i] = 2.0 * xv[i];
+            assert_eq!(row, &diag);
+        }
+
+        let g = |x: Expr<f32, TestEv>| x.clone() * &x * &x;
+        let x: Expr<f32, TestEv> = param(shape![1]);
+        let hes = hessian(&mut e, &g, &x);
+        assert_eq!(hes.len(), 1);
+        let v = hvp(&mut e, &g, &x, ones(shape![1]));
+        let out = run(&mut e, &[(&x, &[1.5])], &[hes[0].clone(), v]);
+        assert_eq!(out, [[9.0], [9.0]]);
+    }
+
+    #[test]
+    fn test_hessian_keeps_grads() {
+        let mut e = TestEv::new();
+        let w: Expr<f32, TestEv> = param(shape![1]);
+        let h = |x: Expr<f32, TestEv>| x.clone() * &x * &x * &w;
+        let x: Expr<f32, TestEv> = param(shape![1]);
+
+        // A training step leaves gradients that later Hessians must not clear
+        h(x.clone()).backward(&mut e);
+        let (gw, gx) = (w.grad().unwrap(), x.grad().unwrap());
+        let hes = hessian(&mut e, &h, &x);
+        assert_eq!(w.grad().unwrap().key(), gw.key());
+        assert_eq!(x.grad().unwrap().key(), gx.key());
+
+        let out = run(
+            &mut e,
+            &[(&x, &[2.0]), (&w, &[3.0])],
+            &[gw, gx, hes[0].clone()],
+        );
+        assert_eq!(out, [[8.0], [36.0], [36.0]]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hessian_non_scalar() {
+        let mut e = TestEv::new();
+        let x: Expr<f32, TestEv> = param(shape![3]);
+        hessian(&mut e, &|x: Expr<f32, TestEv>| x.clone() * &x, &x);
+    }
+
+    #[test]
+    fn test_transforms() {
+        let mut e = TestEv::new();
+        let w: Expr<f32, TestEv> = param(shape![1]);
+        let f = {
+            let w = w.clone();
+            move |x: Expr<f32, TestEv>| x.clone() * &x * &w
         };
-        let module = model::<f32, TestEv>();
-        let out = module.forward(param(shape![0]));
-        let out = out.eval(&mut e);
-        println!("{e:#?}")
+
+        let df = grad(f.clone());
+        let x: Expr<f32, TestEv> = param(shape![1]);
+        let g0 = df(&mut e, x.clone());
+        // No gradients are left behind in the graph
+        assert!(x.grad().is_none());
+
+        let (y, g1) = value_and_grad(f.clone())(&mut e, x.clone());
+        let (_, g2) = vjp(f.clone())(&mut e, x.clone(), ones(shape![1]));
+        let (_, t) = jvp(f)(&mut e, x.clone(), ones(shape![1]));
+        assert!(x.tangent().is_none());
+
+        // Values at x = 3, w = 2, where f = 18 and df/dx = 2xw = 12
+        let out = run(&mut e, &[(&x, &[3.0]), (&w, &[2.0])], &[y, g0, g1, g2, t]);
+        assert_eq!(out, [[18.0], [12.0], [12.0], [12.0], [12.0]]);
     }
 }
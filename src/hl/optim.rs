@@ -0,0 +1,76 @@
+use crate::hl::expr::constant::full;
+use crate::hl::expr::{Eval, Expr, Value};
+
+/// Updates params from their gradients. Steps build the new values as expressions, which are
+/// evaluated like any other.
+pub trait Optimizer {
+    /// Value of `p` after one step along its gradient, `None` if it has no gradient
+    fn step<T: Value, E: Eval>(&mut self, p: &Expr<T, E>) -> Option<Expr<T, E>>;
+}
+
+/// Stochastic gradient descent, `p - lr * grad`. Sparse gradients only update their rows.
+#[derive(Debug, Clone)]
+pub struct Sgd {
+    pub lr: f64,
+}
+
+impl Optimizer for Sgd {
+    fn step<T: Value, E: Eval>(&mut self, p: &Expr<T, E>) -> Option<Expr<T, E>> {
+        let scaled = |g: Expr<E::Grad, E>| {
+            let lr = full::<E::Grad, E>(g.shape().clone(), -self.lr);
+            (g * lr).astype::<T>()
+        };
+        let dense = p.0.grad.borrow().clone();
+        let sparse = p.sparse_grad();
+        if dense.is_none() && sparse.is_none() {
+            return None;
+        }
+
+        let mut out = p.clone();
+        if let Some(g) = dense {
+            out = out + scaled(g);
+        }
+        if let Some(g) = sparse {
+            out = out.index_add(0, g.indices, scaled(g.values));
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::constant::constant;
+    use crate::hl::expr::embedding::embedding;
+    use crate::hl::expr::param::param;
+    use crate::hl::expr::{Eval, Expr};
+    use crate::hl::optim::{Optimizer, Sgd};
+    use crate::hl::test::TestEv;
+    use crate::ll::cpu::Cpu;
+    use crate::shape;
+    use ndarray::{arr1, arr2};
+
+    #[test]
+    fn test_sgd() {
+        let mut e = TestEv::new();
+        let table: Expr<f32, TestEv> = param(shape![3, 2]);
+        let bias: Expr<f32, TestEv> = param(shape![2]);
+        let idx = constant(shape![2], vec![2.0, 2.0]);
+        let y = embedding(&table, idx) + &bias;
+        y.backward(&mut e);
+
+        let mut sgd = Sgd { lr: 0.5 };
+        let (t, b) = (sgd.step(&table).unwrap(), sgd.step(&bias).unwrap());
+        assert!(sgd.step(&param::<f32, TestEv>(shape![1])).is_none());
+
+        let (tb, bb) = (table.eval(&mut e), bias.eval(&mut e));
+        let outs = [t.eval(&mut e), b.eval(&mut e)];
+        let mut cpu = Cpu::new();
+        cpu.set(tb, arr2(&[[1.0, 1.0], [2.0, 2.0], [3.0, 3.0]]).into_dyn());
+        cpu.set(bb, arr1(&[0.0, 0.0]).into_dyn());
+        let bld = e.emitter();
+        // The looked up row is hit twice, the others keep their values
+        let updated = arr2(&[[1.0, 1.0], [2.0, 2.0], [2.0, 2.0]]);
+        assert_eq!(cpu.get(bld, outs[0]), updated.into_dyn());
+        assert_eq!(cpu.get(bld, outs[1]), arr1(&[-1.0, -1.0]).into_dyn());
+    }
+}
@@ -21,6 +21,12 @@ impl Shape {
         d.remove(p);
         Shape { dims: d }
     }
+    pub fn insert(&self, i: isize, dim: usize) -> Shape {
+        let mut d = self.dims.clone();
+        let p = self.wrap(i);
+        d.insert(p, dim);
+        Shape { dims: d }
+    }
     pub fn set(&self, i: isize, dim: usize) -> Shape {
         let mut d = self.dims.clone();
         let p = self.wrap(i);
@@ -130,4 +136,35 @@ impl Shape {
     pub fn prod(&self) -> usize {
         self.dims.iter().product()
     }
+
+    /// Shape both `self` and `other` broadcast to under NumPy rules, if there is one
+    pub fn broadcast_with(&self, other: &Shape) -> Option<Shape> {
+        let n = self.dims.len().max(other.dims.len());
+        let dim = |s: &Shape, i: usize| match (i + s.dims.len()).checked_sub(n) {
+            Some(j) => s.dims[j],
+            None => 1,
+        };
+        (0..n)
+            .map(|i| match (dim(self, i), dim(other, i)) {
+                (a, b) if a == b || b == 1 => Some(a),
+                (1, b) => Some(b),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(Shape::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::shape::Shape;
+
+    #[test]
+    fn test_broadcast_with() {
+        let b = |l: &[usize], r: &[usize]| Shape::from(l).broadcast_with(&r.into());
+        assert_eq!(b(&[2, 3], &[1]), Some(shape![2, 3]));
+        assert_eq!(b(&[2, 1, 3], &[4, 1]), Some(shape![2, 4, 3]));
+        assert_eq!(b(&[], &[5]), Some(shape![5]));
+        assert_eq!(b(&[2, 3], &[3, 2]), None);
+    }
 }
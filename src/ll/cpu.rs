@@ -0,0 +1,546 @@
+use crate::hl::expr::{Value, C128, C64};
+use crate::hl::shape::Shape;
+use crate::ll::{Backend, BufferT};
+use crate::ml::{BufId, DType, MLBuilder, OpType, PadKind};
+use half::{bf16, f16};
+use ndarray::{concatenate, ArcArray, ArrayD, ArrayView1, Axis, IxDyn, Slice, Zip};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Rounds `v` to the closest value `dtype` can hold
+fn represent(v: C128, dtype: DType) -> C128 {
+    match dtype {
+        DType::Bool => bool::from_c128(v).to_c128(),
+        DType::U8 => u8::from_c128(v).to_c128(),
+        DType::I32 => i32::from_c128(v).to_c128(),
+        DType::I64 => i64::from_c128(v).to_c128(),
+        DType::F16 => f16::from_c128(v).to_c128(),
+        DType::BF16 => bf16::from_c128(v).to_c128(),
+        DType::F32 => f32::from_c128(v).to_c128(),
+        DType::F64 => f64::from_c128(v).to_c128(),
+        DType::C64 => C64::from_c128(v).to_c128(),
+        DType::C128 => v,
+    }
+}
+
+/// Product of the matrices in the last two axes of `a` and `b`, batch axes have to match
+fn matmul(a: &ArrayD<C128>, b: &ArrayD<C128>) -> ArrayD<C128> {
+    let (m, k, n) = (
+        a.shape()[a.ndim() - 2],
+        b.shape()[b.ndim() - 2],
+        b.shape()[b.ndim() - 1],
+    );
+    let batch = &a.shape()[..a.ndim() - 2];
+    let count = batch.iter().product();
+    let a = a.to_shape((count, m, k)).unwrap();
+    let b = b.to_shape((count, k, n)).unwrap();
+
+    let mut out = ndarray::Array3::zeros((count, m, n));
+    for i in 0..count {
+        let prod = a.index_axis(Axis(0), i).dot(&b.index_axis(Axis(0), i));
+        out.index_axis_mut(Axis(0), i).assign(&prod);
+    }
+    let shape: Vec<usize> = batch.iter().copied().chain([m, n]).collect();
+    out.into_shape(shape).unwrap()
+}
+
+/// Adds `before` and `after` elements at the ends of `axis`, filled according to `kind`
+fn pad(x: &ArrayD<C128>, axis: Axis, before: usize, after: usize, kind: PadKind) -> ArrayD<C128> {
+    let n = x.len_of(axis);
+    let fill = |count: usize, at_end: bool| -> ArrayD<C128> {
+        let mut dims = x.raw_dim();
+        dims[axis.0] = count;
+        match kind {
+            PadKind::Zero => ArrayD::zeros(dims),
+            PadKind::One => ArrayD::ones(dims),
+            PadKind::Edge => {
+                let edge = if at_end { n - 1 } else { 0 };
+                let edge = x.slice_axis(axis, Slice::from(edge..edge + 1));
+                edge.broadcast(dims).unwrap().to_owned()
+            }
+            // Reflected around the edge element, which is not repeated
+            PadKind::Mirror if at_end => x
+                .slice_axis(axis, Slice::new((n - 1 - count) as isize, Some(-1), -1))
+                .to_owned(),
+            PadKind::Mirror => x
+                .slice_axis(axis, Slice::new(1, Some(count as isize + 1), -1))
+                .to_owned(),
+        }
+    };
+    concatenate(
+        axis,
+        &[
+            fill(before, false).view(),
+            x.view(),
+            fill(after, true).view(),
+        ],
+    )
+    .unwrap()
+}
+
+/// Indices held in `idx`, flattened, checked against an axis of `n` elements
+fn indices(idx: &ArrayD<C128>, n: usize) -> Vec<usize> {
+    idx.iter()
+        .map(|i| {
+            let i = i.re as i64;
+            assert!(
+                0 <= i && (i as usize) < n,
+                "Index {i} out of bounds of axis of {n}"
+            );
+            i as usize
+        })
+        .collect()
+}
+
+/// First element of `lane` that no later one is `better` than, with its index. Compares real
+/// parts.
+fn pick(lane: ArrayView1<C128>, better: impl Fn(f64, f64) -> bool) -> (usize, C128) {
+    let mut best = (0, lane[0]);
+    for (i, v) in lane.iter().enumerate() {
+        if better(v.re, best.1.re) {
+            best = (i, *v);
+        }
+    }
+    best
+}
+
+/// Reference backend. Interprets [`MLBuilder`] graphs with ndarray, computing everything in complex
+/// f64. Real values have zero imaginary parts.
+///
+/// Meant for testing and checking other backends, not for speed.
+#[derive(Debug, Default)]
+pub struct Cpu {
+    inputs: HashMap<BufId, ArrayD<C128>>,
+    // Op outputs computed since the inputs last changed
+    cache: HashMap<BufId, ArrayD<C128>>,
+}
+
+impl Cpu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets value of a real input buffer
+    pub fn set(&mut self, id: BufId, v: ArrayD<f64>) {
+        self.set_complex(id, v.mapv(C128::from));
+    }
+
+    /// Sets value of an input buffer
+    pub fn set_complex(&mut self, id: BufId, v: ArrayD<C128>) {
+        self.inputs.insert(id, v);
+        self.cache.clear();
+    }
+
+    /// Sets value of an input buffer from an uploaded buffer
+    pub fn bind(&mut self, id: BufId, buf: &CpuBuffer) {
+        self.set_complex(id, buf.data.borrow().clone());
+    }
+
+    /// Real part of [`Cpu::get_complex`]
+    pub fn get(&mut self, b: &MLBuilder, id: BufId) -> ArrayD<f64> {
+        self.get_complex(b, id).mapv(|v| v.re)
+    }
+
+    /// Computes value of `id`, along with whatever it depends on
+    pub fn get_complex(&mut self, b: &MLBuilder, id: BufId) -> ArrayD<C128> {
+        if let Some(v) = self.inputs.get(&id).or_else(|| self.cache.get(&id)) {
+            return v.clone();
+        }
+        let dims = IxDyn(&b.shape(id)[..]);
+
+        // Real ops keep real semantics, so they produce NaN instead of complex results
+        let dtype = b.dtype(id);
+        let complex = dtype.is_complex();
+        let mut out = if let Some(data) = b.constant_of(id) {
+            if data.len() == 1 {
+                ArrayD::from_elem(dims, data[0])
+            } else {
+                ArrayD::from_shape_vec(dims, data.to_vec()).unwrap()
+            }
+        } else {
+            let info = b
+                .op(id)
+                .unwrap_or_else(|| panic!("No value provided for buffer {id:?}"));
+            let bool = |v: bool| C128::from(v as u8 as f64);
+            let mut src = |i: usize| self.get_complex(b, info.srcs[i]);
+            let x = src(0);
+
+            match &info.op {
+                OpType::Add => x + src(1),
+                OpType::Mul => x * src(1),
+                OpType::Div if !dtype.is_float() => Zip::from(&x)
+                    .and(&src(1))
+                    .map_collect(|a, b| (a.re / b.re).trunc().into()),
+                OpType::Div => x / src(1),
+                OpType::Pow if complex => Zip::from(&x).and(&src(1)).map_collect(|a, b| a.powc(*b)),
+                OpType::Pow => Zip::from(&x)
+                    .and(&src(1))
+                    .map_collect(|a, b| a.re.powf(b.re).into()),
+                OpType::Eq => Zip::from(&x).and(&src(1)).map_collect(|a, b| bool(a == b)),
+                OpType::Ne => Zip::from(&x).and(&src(1)).map_collect(|a, b| bool(a != b)),
+                OpType::Lt => Zip::from(&x)
+                    .and(&src(1))
+                    .map_collect(|a, b| bool(a.re < b.re)),
+                OpType::Le => Zip::from(&x)
+                    .and(&src(1))
+                    .map_collect(|a, b| bool(a.re <= b.re)),
+                OpType::Minimum => Zip::from(&x)
+                    .and(&src(1))
+                    .map_collect(|a, b| a.re.min(b.re).into()),
+                OpType::Maximum => Zip::from(&x)
+                    .and(&src(1))
+                    .map_collect(|a, b| a.re.max(b.re).into()),
+
+                OpType::Select => Zip::from(&x)
+                    .and(&src(1))
+                    .and(&src(2))
+                    .map_collect(|c, a, b| if c.re != 0.0 { *a } else { *b }),
+
+                // Rounded to the new type along with every other result
+                OpType::Cast { .. } => x,
+                OpType::Neg => -x,
+                OpType::Rec => x.mapv(|v| v.inv()),
+                OpType::Exp => x.mapv(|v| v.exp()),
+                OpType::Log if complex => x.mapv(|v| v.ln()),
+                OpType::Log => x.mapv(|v| v.re.ln().into()),
+                OpType::Gtz => x.mapv(|v| bool(v.re > 0.0)),
+                OpType::Conj => x.mapv(|v| v.conj()),
+                OpType::Sqrt if complex => x.mapv(|v| v.sqrt()),
+                OpType::Sqrt => x.mapv(|v| v.re.sqrt().into()),
+                OpType::Rsqrt if complex => x.mapv(|v| v.sqrt().inv()),
+                OpType::Rsqrt => x.mapv(|v| v.re.sqrt().recip().into()),
+                OpType::Sin => x.mapv(|v| v.sin()),
+                OpType::Cos => x.mapv(|v| v.cos()),
+                OpType::Tanh if complex => x.mapv(|v| v.tanh()),
+                OpType::Tanh => x.mapv(|v| v.re.tanh().into()),
+                OpType::Sign => x.mapv(|v| match v.re {
+                    0.0 => 0.0.into(),
+                    r => r.signum().into(),
+                }),
+                OpType::Floor => x.mapv(|v| C128::new(v.re.floor(), v.im.floor())),
+                OpType::Ceil => x.mapv(|v| C128::new(v.re.ceil(), v.im.ceil())),
+                OpType::Round => {
+                    x.mapv(|v| C128::new(v.re.round_ties_even(), v.im.round_ties_even()))
+                }
+                OpType::Erf => x.mapv(|v| libm::erf(v.re).into()),
+                OpType::Expm1 if complex => x.mapv(|v| v.exp() - 1.0),
+                OpType::Expm1 => x.mapv(|v| v.re.exp_m1().into()),
+                OpType::Log1p if complex => x.mapv(|v| (v + 1.0).ln()),
+                OpType::Log1p => x.mapv(|v| v.re.ln_1p().into()),
+
+                OpType::Abs => x.mapv(|v| v.norm().into()),
+                OpType::Angle => x.mapv(|v| v.arg().into()),
+                OpType::Real => x.mapv(|v| v.re.into()),
+                OpType::Imag => x.mapv(|v| v.im.into()),
+
+                OpType::MatMul => matmul(&x, &src(1)),
+
+                OpType::Sum { axis } => x.sum_axis(Axis(*axis)),
+                OpType::Max { axis } => x.map_axis(Axis(*axis), |l| pick(l, |a, b| a > b).1),
+                OpType::Min { axis } => x.map_axis(Axis(*axis), |l| pick(l, |a, b| a < b).1),
+                OpType::Prod { axis } => x.map_axis(Axis(*axis), |l| l.product()),
+                OpType::ArgMax { axis } => {
+                    x.map_axis(Axis(*axis), |l| (pick(l, |a, b| a > b).0 as f64).into())
+                }
+                OpType::ArgMin { axis } => {
+                    x.map_axis(Axis(*axis), |l| (pick(l, |a, b| a < b).0 as f64).into())
+                }
+
+                OpType::Broadcast { axis, .. } => x
+                    .insert_axis(Axis(*axis))
+                    .broadcast(dims)
+                    .unwrap()
+                    .to_owned(),
+                OpType::Cat { axis } => {
+                    concatenate(Axis(*axis), &[x.view(), src(1).view()]).unwrap()
+                }
+                OpType::Flip { axis } => x
+                    .slice_axis(Axis(*axis), Slice::new(0, None, -1))
+                    .to_owned(),
+                OpType::Permute { axes } => {
+                    x.permuted_axes(IxDyn(axes)).as_standard_layout().to_owned()
+                }
+                OpType::Pad {
+                    axis,
+                    before,
+                    after,
+                    kind,
+                } => pad(&x, Axis(*axis), *before, *after, *kind),
+                OpType::Reshape { shape } => x.to_shape(IxDyn(&shape[..])).unwrap().to_owned(),
+                OpType::Slice {
+                    axis,
+                    start,
+                    len,
+                    step,
+                } => {
+                    let end = match len {
+                        0 => *start,
+                        _ => start + (len - 1) * step + 1,
+                    };
+                    let slice = Slice::new(*start as isize, Some(end as isize), *step as isize);
+                    x.slice_axis(Axis(*axis), slice).to_owned()
+                }
+                OpType::Gather { axis } => {
+                    let idx = indices(&src(1), x.len_of(Axis(*axis)));
+                    let picked = x.select(Axis(*axis), &idx);
+                    picked.into_shape(dims).unwrap()
+                }
+                OpType::IndexAdd { axis } => {
+                    let idx = indices(&src(1), x.len_of(Axis(*axis)));
+                    let values = src(2);
+                    let mut values_dims = x.raw_dim();
+                    values_dims[*axis] = idx.len();
+                    let values = values.to_shape(values_dims).unwrap();
+                    let mut out = x;
+                    for (j, i) in idx.iter().enumerate() {
+                        let mut lane = out.index_axis_mut(Axis(*axis), *i);
+                        lane += &values.index_axis(Axis(*axis), j);
+                    }
+                    out
+                }
+            }
+        };
+
+        // Results hold what their type can, so real ones drop imaginary parts, like the NaN from
+        // `inf * 0` in overflowing exponents
+        out.mapv_inplace(|v| represent(v, dtype));
+        // Views like flips and slices keep their strides, results are always in standard layout
+        if !out.is_standard_layout() {
+            out = out.as_standard_layout().into_owned();
+        }
+        self.cache.insert(id, out.clone());
+        out
+    }
+}
+
+#[derive(Debug)]
+pub struct CpuBuffer {
+    dtype: DType,
+    data: RefCell<ArrayD<C128>>,
+}
+
+impl BufferT<Cpu> for CpuBuffer {
+    fn upload<T: Value>(&self, e: &mut Cpu, n: ArcArray<T, IxDyn>) {
+        assert_eq!(
+            T::DTYPE,
+            self.dtype,
+            "Uploading to a buffer of different type"
+        );
+        *self.data.borrow_mut() = n.mapv(T::to_c128);
+    }
+
+    fn download<T: Value>(&self, shape: &Shape, e: &mut Cpu) -> ArcArray<T, IxDyn> {
+        assert_eq!(
+            T::DTYPE,
+            self.dtype,
+            "Downloading from a buffer of different type"
+        );
+        let data = self.data.borrow();
+        data.to_shape(IxDyn(&shape[..]))
+            .unwrap()
+            .mapv(T::from_c128)
+            .into_shared()
+    }
+}
+
+impl Backend for Cpu {
+    type Buffer = CpuBuffer;
+
+    fn alloc(&mut self, shp: &Shape, dtype: DType) -> Self::Buffer {
+        CpuBuffer {
+            dtype,
+            data: RefCell::new(ArrayD::zeros(IxDyn(&shp[..]))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hl::expr::C64;
+    use crate::ll::cpu::Cpu;
+    use crate::ll::{Backend, BufferT};
+    use crate::ml::{DType, MLBuilder, OpType, PadKind};
+    use crate::shape;
+    use half::f16;
+    use ndarray::{arr1, arr2};
+
+    #[test]
+    fn test_run() {
+        let mut bld = MLBuilder::new();
+        let a = bld.buffer(shape![2], DType::F32);
+        let c = bld.constant(shape![2], DType::F32, vec![1.0.into(), 2.0.into()]);
+        let s = bld.emit(OpType::Add, &shape![2], a, c);
+        let b = bld.emit(
+            OpType::Broadcast { axis: 0, count: 3 },
+            &shape![3, 2],
+            s,
+            Default::default(),
+        );
+        let r = bld.emit(OpType::Sum { axis: 1 }, &shape![3], b, Default::default());
+
+        let mut cpu = Cpu::new();
+        cpu.set(a, arr1(&[3.0, 4.0]).into_dyn());
+        assert_eq!(
+            cpu.get(&bld, b),
+            arr2(&[[4.0, 6.0], [4.0, 6.0], [4.0, 6.0]]).into_dyn()
+        );
+        assert_eq!(cpu.get(&bld, r), arr1(&[10.0, 10.0, 10.0]).into_dyn());
+
+        cpu.set(a, arr1(&[0.0, 0.0]).into_dyn());
+        assert_eq!(cpu.get(&bld, r), arr1(&[3.0, 3.0, 3.0]).into_dyn());
+    }
+
+    #[test]
+    fn test_dtypes() {
+        let mut bld = MLBuilder::new();
+        let a = bld.buffer(shape![3], DType::F64);
+        let i = bld.emit(
+            OpType::Cast { to: DType::I32 },
+            &shape![3],
+            a,
+            Default::default(),
+        );
+        let m = bld.emit(
+            OpType::Cast { to: DType::Bool },
+            &shape![3],
+            a,
+            Default::default(),
+        );
+
+        let mut cpu = Cpu::new();
+        let buf = cpu.alloc(&shape![3], DType::F64);
+        buf.upload(&mut cpu, arr1(&[1.5, -2.7, 0.0]).into_dyn().into_shared());
+        cpu.bind(a, &buf);
+        assert_eq!(cpu.get(&bld, i), arr1(&[1.0, -2.0, 0.0]).into_dyn());
+        assert_eq!(cpu.get(&bld, m), arr1(&[1.0, 1.0, 0.0]).into_dyn());
+
+        let mask = cpu.alloc(&shape![2], DType::Bool);
+        mask.upload(&mut cpu, arr1(&[true, false]).into_dyn().into_shared());
+        assert_eq!(
+            mask.download::<bool>(&shape![2], &mut cpu),
+            arr1(&[true, false]).into_dyn().into_shared()
+        );
+
+        let z = bld.buffer(shape![2], DType::C64);
+        let r = bld.emit(OpType::Abs, &shape![2], z, Default::default());
+        assert_eq!(bld.dtype(r), DType::F32);
+        let buf = cpu.alloc(&shape![2], DType::C64);
+        let v = arr1(&[C64::new(3.0, 4.0), C64::new(0.0, -1.0)]).into_dyn();
+        buf.upload(&mut cpu, v.clone().into_shared());
+        assert_eq!(buf.download::<C64>(&shape![2], &mut cpu), v.into_shared());
+        cpu.bind(z, &buf);
+        assert_eq!(cpu.get(&bld, r), arr1(&[5.0, 1.0]).into_dyn());
+    }
+
+    #[test]
+    fn test_i32() {
+        let mut bld = MLBuilder::new();
+        let a = bld.buffer(shape![4], DType::I32);
+        let b = bld.buffer(shape![4], DType::I32);
+        let q = bld.emit(OpType::Div, &shape![4], a, b);
+        let r = bld.emit(OpType::Rec, &shape![4], b, Default::default());
+
+        let mut cpu = Cpu::new();
+        cpu.set(a, arr1(&[7.0, -7.0, 9.0, 1.0]).into_dyn());
+        cpu.set(b, arr1(&[2.0, 2.0, -4.0, 3.0]).into_dyn());
+        assert_eq!(cpu.get(&bld, q), arr1(&[3.0, -3.0, -2.0, 0.0]).into_dyn());
+        assert_eq!(cpu.get(&bld, r), arr1(&[0.0, 0.0, 0.0, 0.0]).into_dyn());
+    }
+
+    #[test]
+    fn test_f16() {
+        let mut bld = MLBuilder::new();
+        let a = bld.buffer(shape![2], DType::F16);
+        let c = bld.constant(shape![2], DType::F16, vec![1.0.into(), 1.0.into()]);
+        let s = bld.emit(OpType::Add, &shape![2], a, c);
+        let r = bld.emit(OpType::Rec, &shape![2], a, Default::default());
+
+        let mut cpu = Cpu::new();
+        cpu.set(a, arr1(&[2048.0, 3.0]).into_dyn());
+        // f16 has a spacing of 2 at 2048
+        assert_eq!(cpu.get(&bld, s), arr1(&[2048.0, 4.0]).into_dyn());
+        let third = f16::from_f64(1.0 / 3.0).to_f64();
+        assert_eq!(cpu.get(&bld, r)[1], third);
+        assert_ne!(third, 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_unary() {
+        let mut bld = MLBuilder::new();
+        let a = bld.buffer(shape![4], DType::F32);
+        let mut cpu = Cpu::new();
+        cpu.set(a, arr1(&[0.5, 1.5, -2.5, 0.0]).into_dyn());
+
+        let mut run = |op| {
+            let y = bld.emit(op, &shape![4], a, Default::default());
+            cpu.get(&bld, y)
+        };
+        assert_eq!(run(OpType::Round), arr1(&[0.0, 2.0, -2.0, 0.0]).into_dyn());
+        assert_eq!(run(OpType::Sign), arr1(&[1.0, 1.0, -1.0, 0.0]).into_dyn());
+        assert_eq!(run(OpType::Floor), arr1(&[0.0, 1.0, -3.0, 0.0]).into_dyn());
+        assert!(run(OpType::Sqrt)[2].is_nan());
+        assert_eq!(run(OpType::Erf)[3], 0.0);
+    }
+
+    #[test]
+    fn test_shape_ops() {
+        let mut bld = MLBuilder::new();
+        let a = bld.buffer(shape![2, 3], DType::F32);
+        let mut cpu = Cpu::new();
+        cpu.set(a, arr2(&[[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn());
+
+        let mut run = |op, shape| {
+            let y = bld.emit(op, &shape, a, Default::default());
+            cpu.get(&bld, y)
+        };
+        let pad = |before, after, kind| OpType::Pad {
+            axis: 1,
+            before,
+            after,
+            kind,
+        };
+        assert_eq!(
+            run(pad(1, 2, PadKind::Zero), shape![2, 6]),
+            arr2(&[
+                [0.0, 1.0, 2.0, 3.0, 0.0, 0.0],
+                [0.0, 4.0, 5.0, 6.0, 0.0, 0.0]
+            ])
+            .into_dyn()
+        );
+        assert_eq!(
+            run(pad(2, 1, PadKind::Edge), shape![2, 6]),
+            arr2(&[
+                [1.0, 1.0, 1.0, 2.0, 3.0, 3.0],
+                [4.0, 4.0, 4.0, 5.0, 6.0, 6.0]
+            ])
+            .into_dyn()
+        );
+        assert_eq!(
+            run(pad(2, 1, PadKind::Mirror), shape![2, 6]),
+            arr2(&[
+                [3.0, 2.0, 1.0, 2.0, 3.0, 2.0],
+                [6.0, 5.0, 4.0, 5.0, 6.0, 5.0]
+            ])
+            .into_dyn()
+        );
+
+        let shape = shape![3, 2];
+        let reshape = OpType::Reshape {
+            shape: shape.clone(),
+        };
+        assert_eq!(
+            run(reshape, shape),
+            arr2(&[[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]).into_dyn()
+        );
+        let slice = OpType::Slice {
+            axis: 1,
+            start: 1,
+            len: 2,
+            step: 1,
+        };
+        assert_eq!(
+            run(slice, shape![2, 2]),
+            arr2(&[[2.0, 3.0], [5.0, 6.0]]).into_dyn()
+        );
+    }
+}
@@ -1,14 +1,18 @@
+pub mod cpu;
+
+use crate::hl::expr::Value;
 use crate::hl::shape::Shape;
+use crate::ml::DType;
 use ndarray::{ArcArray, IxDyn};
 
 /// Every tensor implementation must be able to materialize the generated tensor
 pub trait BufferT<E: Backend<Buffer = Self>>: Sized {
-    fn upload<T>(&self, e: &mut E, n: ArcArray<T, IxDyn>);
-    fn download<T>(&self, shape: &Shape, e: &mut E) -> ArcArray<T, IxDyn>;
+    fn upload<T: Value>(&self, e: &mut E, n: ArcArray<T, IxDyn>);
+    fn download<T: Value>(&self, shape: &Shape, e: &mut E) -> ArcArray<T, IxDyn>;
 }
 
 pub trait Backend: Sized {
     type Buffer: BufferT<Self>;
-    fn alloc(&mut self, shp: &Shape) -> Self::Buffer;
+    fn alloc(&mut self, shp: &Shape, dtype: DType) -> Self::Buffer;
     // fn begin(&mut self) -> ShaderBuilder<Self>;
 }
@@ -1,6 +1,7 @@
 use crate::hl::shape::Shape;
 use indexmap::map::Entry;
 use indexmap::{IndexMap, IndexSet};
+use num::Complex;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::BuildHasher;
 
@@ -62,7 +63,7 @@ fn test() {
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub enum PadKind {
     // Pad with zeroes
     Zero,
@@ -74,34 +75,143 @@ pub enum PadKind {
     Edge,
 }
 
+/// Element type of a buffer. Ordered by promotion rank.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Hash, Ord, PartialOrd, Eq, PartialEq)]
+pub enum DType {
+    Bool,
+    U8,
+    I32,
+    I64,
+    F16,
+    BF16,
+    F32,
+    F64,
+    /// Complex with `F32` parts
+    C64,
+    /// Complex with `F64` parts
+    C128,
+}
+
+impl DType {
+    /// Floating point, real or complex
+    pub fn is_float(self) -> bool {
+        self >= DType::F16
+    }
+
+    pub fn is_complex(self) -> bool {
+        self >= DType::C64
+    }
+
+    /// Type of the real and imaginary parts, or `self` for real types
+    pub fn real(self) -> DType {
+        match self {
+            DType::C64 => DType::F32,
+            DType::C128 => DType::F64,
+            d => d,
+        }
+    }
+
+    /// Type of the result of a binary op mixing `self` and `other`.
+    ///
+    /// The higher ranked type wins, so integers promote to floats of any width. `F16` and `BF16`
+    /// can't represent each other, they meet at `F32`. Complex results are wide enough to hold the
+    /// promoted real parts.
+    pub fn promote(self, other: DType) -> DType {
+        match (self, other) {
+            (DType::F16, DType::BF16) | (DType::BF16, DType::F16) => DType::F32,
+            (a, b) if a.is_complex() || b.is_complex() => match a.real().promote(b.real()) {
+                DType::F64 => DType::C128,
+                _ => DType::C64,
+            },
+            (a, b) => a.max(b),
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub enum OpType {
     // Binary ops
     Add,
     Mul,
+    /// Division, rounding towards zero for integers
+    Div,
     Pow,
     Eq,
+    Ne,
+    Lt,
+    Le,
+    Minimum,
+    Maximum,
+
+    // Ternary ops
+    /// Elements of the second input where the first is true, of the third elsewhere
+    Select,
 
     // Unary ops
+    /// Converts elements to another type
+    Cast {
+        to: DType,
+    },
     Neg,
     Rec,
     Exp,
     Log,
     Gtz,
+    Conj,
+    Sqrt,
+    Rsqrt,
+    Sin,
+    Cos,
+    Tanh,
+    Sign,
+    Floor,
+    Ceil,
+    Round,
+    Erf,
+    Expm1,
+    Log1p,
+
+    // Complex parts, real valued
+    Abs,
+    Angle,
+    Real,
+    Imag,
 
     // Matrix ops
+    /// Product of the matrices in the last two axes, `[B.., m, k] @ [B.., k, n] -> [B.., m, n]`
     MatMul,
 
-    // Reduce ops
-    Max,
-    Sum,
+    // Reduce ops, removing `axis` from the shape
+    Sum {
+        axis: usize,
+    },
+    Max {
+        axis: usize,
+    },
+    Min {
+        axis: usize,
+    },
+    Prod {
+        axis: usize,
+    },
+    /// Index of the largest element, the first one on ties
+    ArgMax {
+        axis: usize,
+    },
+    /// Index of the smallest element, the first one on ties
+    ArgMin {
+        axis: usize,
+    },
 
     //Shape ops
+    /// Inserts a new axis at `axis`, repeating the input `count` times along it
     Broadcast {
         axis: usize,
         count: usize,
     },
+    /// Joins both inputs along `axis`
     Cat {
         axis: usize,
     },
@@ -111,11 +221,34 @@ pub enum OpType {
     Permute {
         axes: Vec<usize>,
     },
+    /// Adds `before` and `after` elements at the ends of `axis`
     Pad {
         axis: usize,
-        amt: usize,
+        before: usize,
+        after: usize,
         kind: PadKind,
     },
+    /// Same elements in row-major order, with a new shape
+    Reshape {
+        shape: Shape,
+    },
+    /// `len` elements of `axis` from `start` on, every `step`-th one
+    Slice {
+        axis: usize,
+        start: usize,
+        len: usize,
+        step: usize,
+    },
+
+    // Indexing ops, indices are the second input
+    /// Elements of the first input at the indices along `axis`, which is replaced by their axes
+    Gather {
+        axis: usize,
+    },
+    /// The first input with the third added at the indices along `axis`
+    IndexAdd {
+        axis: usize,
+    },
 }
 
 #[derive(Debug, Default, Clone, Copy, Hash, Ord, PartialOrd, Eq, PartialEq)]
@@ -124,18 +257,15 @@ pub struct BufId(u64);
 #[derive(Debug, Clone, Hash, Ord, PartialOrd, Eq, PartialEq)]
 pub struct MLOp {
     op: OpType,
-    src1: BufId,
-    src2: BufId,
+    srcs: Vec<BufId>,
 }
 
 #[derive(Debug)]
 pub struct OpInfo {
-    op: OpType,
-    osh: Shape,
-
-    src1: BufId,
-
-    src2: BufId,
+    pub op: OpType,
+    pub osh: Shape,
+    /// Inputs, in the order the op takes them
+    pub srcs: Vec<BufId>,
 }
 
 struct ZeroInit;
@@ -153,6 +283,10 @@ pub struct MLBuilder {
     maxid: u64,
 
     buffs: IndexMap<BufId, Shape, ZeroInit>,
+    // Element type of every buffer and op output
+    types: IndexMap<BufId, DType, ZeroInit>,
+    // Buffers with values known at build time
+    consts: IndexMap<BufId, Vec<Complex<f64>>, ZeroInit>,
     nodes: IndexMap<MLOp, BufId, ZeroInit>,
     instr: IndexMap<BufId, OpInfo, ZeroInit>,
 
@@ -168,6 +302,8 @@ impl MLBuilder {
         Self {
             maxid: 0,
             buffs: IndexMap::with_hasher(ZeroInit),
+            types: IndexMap::with_hasher(ZeroInit),
+            consts: IndexMap::with_hasher(ZeroInit),
             nodes: IndexMap::with_hasher(ZeroInit),
             instr: IndexMap::with_hasher(ZeroInit),
             shap: IndexMap::with_hasher(ZeroInit),
@@ -175,22 +311,59 @@ impl MLBuilder {
         }
     }
 
+    /// Shape of a buffer or of an op output
+    pub fn shape(&self, id: BufId) -> &Shape {
+        self.buffs.get(&id).or_else(|| self.shap.get(&id)).unwrap()
+    }
+
+    /// Element type of a buffer or of an op output
+    pub fn dtype(&self, id: BufId) -> DType {
+        self.types[&id]
+    }
+
+    /// Values of a constant buffer
+    pub fn constant_of(&self, id: BufId) -> Option<&[Complex<f64>]> {
+        self.consts.get(&id).map(|c| c.as_slice())
+    }
+
+    /// Op producing `id`, `None` for buffers
+    pub fn op(&self, id: BufId) -> Option<&OpInfo> {
+        self.instr.get(&id)
+    }
+
     fn newid(&mut self) -> BufId {
         self.maxid += 1;
         BufId(self.maxid)
     }
 
-    pub fn buffer(&mut self, shape: Shape) -> BufId {
+    pub fn buffer(&mut self, shape: Shape, dtype: DType) -> BufId {
         let id = self.newid();
         self.buffs.insert(id, shape);
+        self.types.insert(id, dtype);
+        id
+    }
+
+    /// Buffer initialized with `data`, either one value per element or a single splatted value
+    pub fn constant(&mut self, shape: Shape, dtype: DType, data: Vec<Complex<f64>>) -> BufId {
+        let id = self.buffer(shape, dtype);
+        self.consts.insert(id, data);
         id
     }
 
+    /// Emits a unary or binary op. `src2` of unary ops is the default id.
     pub fn emit(&mut self, op: OpType, osh: &Shape, src1: BufId, src2: BufId) -> BufId {
+        if src2 == BufId::default() {
+            self.emit_n(op, osh, &[src1])
+        } else {
+            self.emit_n(op, osh, &[src1, src2])
+        }
+    }
+
+    /// Emits an op taking any number of inputs
+    pub fn emit_n(&mut self, op: OpType, osh: &Shape, srcs: &[BufId]) -> BufId {
         let mlop = MLOp {
             op: op.clone(),
-            src1,
-            src2,
+            srcs: srcs.to_vec(),
         };
         let outid = match self.nodes.entry(mlop) {
             Entry::Occupied(b) => *b.get(),
@@ -198,15 +371,35 @@ impl MLBuilder {
                 self.maxid += 1;
                 let outid = BufId(self.maxid);
 
+                let dtype = match op {
+                    OpType::Cast { to } => to,
+                    OpType::Eq | OpType::Ne | OpType::Lt | OpType::Le => DType::Bool,
+                    OpType::Abs | OpType::Angle | OpType::Real | OpType::Imag => {
+                        self.types[&srcs[0]].real()
+                    }
+                    OpType::ArgMax { .. } | OpType::ArgMin { .. } => DType::I64,
+                    OpType::Select => self.types[&srcs[1]].promote(self.types[&srcs[2]]),
+                    OpType::Add
+                    | OpType::Mul
+                    | OpType::Div
+                    | OpType::Pow
+                    | OpType::Minimum
+                    | OpType::Maximum
+                    | OpType::MatMul => {
+                        self.types[&srcs[0]].promote(self.types[&srcs[1]])
+                    }
+                    _ => self.types[&srcs[0]],
+                };
+
                 e.insert(outid);
                 self.shap.insert(outid, osh.clone());
+                self.types.insert(outid, dtype);
                 self.instr.insert(
                     outid,
                     OpInfo {
                         op: op.clone(),
                         osh: osh.clone(),
-                        src1,
-                        src2,
+                        srcs: srcs.to_vec(),
                     },
                 );
 
@@ -214,8 +407,9 @@ impl MLBuilder {
             }
         };
 
-        self.deps.entry(src1).or_default().insert(outid);
-        self.deps.entry(src2).or_default().insert(outid);
+        for src in srcs {
+            self.deps.entry(*src).or_default().insert(outid);
+        }
 
         outid
     }
@@ -223,14 +417,14 @@ impl MLBuilder {
 
 #[cfg(test)]
 mod test {
-    use crate::ml::{BufId, MLBuilder, OpType};
+    use crate::ml::{BufId, DType, MLBuilder, OpType};
     use crate::shape;
 
     #[test]
     fn test_emit() {
         let mut bld = MLBuilder::new();
-        let b1 = bld.buffer(shape![1, 1]);
-        let b2 = bld.buffer(shape![1, 1]);
+        let b1 = bld.buffer(shape![1, 1], DType::F32);
+        let b2 = bld.buffer(shape![1, 1], DType::F32);
 
         let b3 = bld.emit(OpType::Add, &shape![], b1, b2);
         assert_eq!(b3, BufId(3));
@@ -244,6 +438,37 @@ mod test {
         let b6 = bld.emit(OpType::Add, &shape![], b3, b4);
         assert_eq!(b6, BufId(5));
 
+        let b7 = bld.emit(OpType::Cast { to: DType::F32 }, &shape![1, 1], b6, BufId::default());
+        assert_eq!(bld.dtype(b7), DType::F32);
+
+        let b8 = bld.buffer(shape![1, 1], DType::BF16);
+        let b9 = bld.emit(OpType::Mul, &shape![1, 1], b1, b8);
+        assert_eq!(bld.dtype(b9), DType::F32);
+        let b10 = bld.emit(OpType::Eq, &shape![1, 1], b1, b8);
+        assert_eq!(bld.dtype(b10), DType::Bool);
+
+        let b11 = bld.emit_n(OpType::Select, &shape![1, 1], &[b10, b1, b8]);
+        assert_eq!(bld.op(b11).unwrap().srcs, vec![b10, b1, b8]);
+        assert_eq!(bld.dtype(b11), DType::F32);
+        assert_eq!(bld.op(b7).unwrap().srcs, vec![b6]);
+
         println!("{:#?}", bld);
     }
+
+    #[test]
+    fn test_promote() {
+        assert_eq!(DType::Bool.promote(DType::U8), DType::U8);
+        assert_eq!(DType::I64.promote(DType::I32), DType::I64);
+        assert_eq!(DType::I64.promote(DType::F16), DType::F16);
+        assert_eq!(DType::BF16.promote(DType::F16), DType::F32);
+        assert_eq!(DType::F32.promote(DType::F64), DType::F64);
+        assert!(!DType::I64.is_float());
+        assert!(DType::BF16.is_float());
+
+        assert_eq!(DType::C64.promote(DType::F64), DType::C128);
+        assert_eq!(DType::F16.promote(DType::C64), DType::C64);
+        assert_eq!(DType::I64.promote(DType::C128), DType::C128);
+        assert_eq!(DType::C64.real(), DType::F32);
+        assert!(DType::C64.is_float());
+    }
 }